@@ -54,7 +54,12 @@ async fn ffutil_version(util: FFUtil) -> anyhow::Result<String> {
 
 pub async fn start_http() -> anyhow::Result<()> {
     use crate::services::{
-        download::download, upload::upload, version::version, websocket::websocket,
+        download::download,
+        formats::formats,
+        preview::{frame, storyboard, storyboard_sprite},
+        upload::{upload, upload_subtitles},
+        version::version,
+        websocket::websocket,
     };
 
     let server = HttpServer::new(|| {
@@ -68,10 +73,15 @@ pub async fn start_http() -> anyhow::Result<()> {
             .service(
                 web::scope("/api")
                     .service(upload)
+                    .service(upload_subtitles)
                     .service(download)
+                    .service(frame)
+                    .service(storyboard_sprite)
+                    .service(storyboard)
                     // .route("/ws", web::get().to(websocket)),
                     .service(websocket)
-                    .service(version),
+                    .service(version)
+                    .service(formats),
             )
     });
     let port = std::env::var("PORT").unwrap_or_else(|_| "24153".to_string());
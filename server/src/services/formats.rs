@@ -0,0 +1,9 @@
+use actix_web::{Responder, get};
+use vertd::converter::format::FormatInfo;
+
+use crate::response::ApiResponse;
+
+#[get("/formats")]
+pub async fn formats() -> impl Responder {
+    ApiResponse::Success(FormatInfo::all())
+}
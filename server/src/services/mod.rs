@@ -1,4 +1,6 @@
 pub mod download;
+pub mod formats;
+pub mod preview;
 pub mod upload;
 pub mod version;
 pub mod websocket;
@@ -0,0 +1,143 @@
+// get /preview/{id}/{token}?time=..., /storyboard/{id}/{token} and /storyboard/{id}/{token}/sprite
+
+use crate::APP_STATE;
+use actix_web::{HttpResponse, Responder, ResponseError, get, web};
+use serde::Deserialize;
+use uuid::Uuid;
+use vertd::converter::{
+    job::Job,
+    options::ImageFormat,
+    preview::{self, Storyboard},
+};
+
+use crate::response::ApiResponse;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PreviewError {
+    #[error("job not found")]
+    JobNotFound,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("job already completed")]
+    JobCompleted,
+    #[error("failed to render preview: {0}")]
+    Render(#[from] anyhow::Error),
+}
+
+impl ResponseError for PreviewError {
+    fn error_response(&self) -> HttpResponse {
+        let status = match self {
+            PreviewError::JobNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            PreviewError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            PreviewError::JobCompleted => actix_web::http::StatusCode::GONE,
+            PreviewError::Render(_) => actix_web::http::StatusCode::BAD_REQUEST,
+        };
+
+        HttpResponse::build(status).json(ApiResponse::<()>::Error(self.to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameQuery {
+    #[serde(default)]
+    time: f64,
+    #[serde(default)]
+    format: ImageFormat,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoryboardQuery {
+    #[serde(default = "default_count")]
+    count: u32,
+    #[serde(default = "default_columns")]
+    columns: u32,
+    #[serde(default = "default_tile_width")]
+    width: u32,
+    #[serde(default = "default_storyboard_format")]
+    format: ImageFormat,
+}
+
+fn default_count() -> u32 {
+    16
+}
+
+fn default_columns() -> u32 {
+    4
+}
+
+fn default_tile_width() -> u32 {
+    160
+}
+
+fn default_storyboard_format() -> ImageFormat {
+    ImageFormat::Jpeg
+}
+
+// previews only make sense while the input is still around, i.e. before the job has run
+async fn authorised_job(id: Uuid, token: &str) -> Result<Job, PreviewError> {
+    let app_state = APP_STATE.lock().await;
+    let job = app_state
+        .jobs
+        .get(&id)
+        .ok_or(PreviewError::JobNotFound)?
+        .clone();
+    drop(app_state);
+
+    if job.auth != token {
+        return Err(PreviewError::InvalidToken);
+    }
+
+    if job.completed {
+        return Err(PreviewError::JobCompleted);
+    }
+
+    Ok(job)
+}
+
+#[get("/preview/{id}/{token}")]
+pub async fn frame(
+    path: web::Path<(Uuid, String)>,
+    query: web::Query<FrameQuery>,
+) -> Result<impl Responder, PreviewError> {
+    let (id, token) = path.into_inner();
+    let mut job = authorised_job(id, &token).await?;
+
+    let bytes = preview::frame_at(&mut job, query.time, &query.format).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", query.format.mime()))
+        .insert_header(("Content-Length", bytes.len()))
+        .body(bytes))
+}
+
+#[get("/storyboard/{id}/{token}")]
+pub async fn storyboard(
+    path: web::Path<(Uuid, String)>,
+    query: web::Query<StoryboardQuery>,
+) -> Result<impl Responder, PreviewError> {
+    let (id, token) = path.into_inner();
+    let mut job = authorised_job(id, &token).await?;
+
+    let board = Storyboard::new(&mut job, query.count, query.columns, query.width).await?;
+
+    Ok(ApiResponse::Success(board))
+}
+
+#[get("/storyboard/{id}/{token}/sprite")]
+pub async fn storyboard_sprite(
+    path: web::Path<(Uuid, String)>,
+    query: web::Query<StoryboardQuery>,
+) -> Result<impl Responder, PreviewError> {
+    let (id, token) = path.into_inner();
+    let mut job = authorised_job(id, &token).await?;
+
+    let board = Storyboard::new(&mut job, query.count, query.columns, query.width).await?;
+    let bytes = board.render(&mut job, &query.format).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", query.format.mime()))
+        .insert_header(("Content-Length", bytes.len()))
+        .body(bytes))
+}
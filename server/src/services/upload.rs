@@ -1,12 +1,13 @@
 use actix_multipart::Multipart;
-use actix_web::{HttpResponse, Responder, ResponseError, post};
+use actix_web::{HttpResponse, Responder, ResponseError, post, web};
 use futures_util::StreamExt as _;
 use log::info;
 use tokio::{
     fs::{self, File},
     io::AsyncWriteExt,
 };
-use vertd::converter::{format::ConverterFormat, job::Job};
+use uuid::Uuid;
+use vertd::converter::{format::ConverterFormat, job::Job, options::SUBTITLE_FILE_EXTENSIONS};
 
 use crate::{APP_STATE, response::ApiResponse};
 
@@ -20,14 +21,20 @@ pub enum UploadError {
     NoFilename,
     #[error("missing file extension")]
     NoExtension,
-    #[error("invalid file extension: {0}. allowed: jpg, png, gif")]
-    InvalidExtension(String),
+    #[error("invalid file extension: {0}. allowed: {1}")]
+    InvalidExtension(String, String),
     #[error("failed to read file data")]
     GetChunk(#[from] actix_web::Error),
     #[error("internal server error while writing file")]
     WriteFile(#[from] std::io::Error),
     #[error("ffprobe failed to read file: {0}")]
     ParseFile(#[from] anyhow::Error),
+    #[error("job not found")]
+    JobNotFound,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("job already completed")]
+    JobCompleted,
 }
 
 impl ResponseError for UploadError {
@@ -37,6 +44,9 @@ impl ResponseError for UploadError {
             UploadError::GetField(_) => actix_web::http::StatusCode::BAD_REQUEST,
             UploadError::GetChunk(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             UploadError::WriteFile(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::JobNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            UploadError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            UploadError::JobCompleted => actix_web::http::StatusCode::GONE,
             _ => actix_web::http::StatusCode::BAD_REQUEST,
         };
 
@@ -66,7 +76,7 @@ pub async fn upload(mut payload: Multipart) -> Result<impl Responder, UploadErro
 
         let ext = filename
             .split('.')
-            .last()
+            .next_back()
             .map(|ext| {
                 ext.chars()
                     .filter(|c| c.is_alphanumeric())
@@ -74,9 +84,17 @@ pub async fn upload(mut payload: Multipart) -> Result<impl Responder, UploadErro
             })
             .ok_or_else(|| UploadError::NoExtension)?;
 
-        if let Err(e) = ext.parse::<ConverterFormat>() {
-            log::error!("failed to parse file extension: {}", e);
-            return Err(UploadError::InvalidExtension(ext));
+        match ext.parse::<ConverterFormat>() {
+            Ok(format) if format.is_input() => {}
+            result => {
+                if let Err(e) = result {
+                    log::error!("failed to parse file extension: {}", e);
+                }
+                return Err(UploadError::InvalidExtension(
+                    ext,
+                    ConverterFormat::input_list(),
+                ));
+            }
         }
 
         info!("uploaded file: {}", filename);
@@ -106,14 +124,91 @@ pub async fn upload(mut payload: Multipart) -> Result<impl Responder, UploadErro
                 our_job.id
             );
             let mut app_state = APP_STATE.lock().await;
-            app_state.jobs.remove(&our_job.id);
+            let removed = app_state.jobs.remove(&our_job.id);
             fs::remove_file(format!("input/{}.{}", our_job.id, ext))
                 .await
                 .ok();
+            if let Some(path) = removed.and_then(|job| job.subtitle_path()) {
+                fs::remove_file(path).await.ok();
+            }
         });
         break;
     }
     let mut job = job.ok_or_else(|| UploadError::NoFile)?;
-    job.total_frames().await?;
+    // audio-only inputs report progress by time, so they need a duration instead
+    if job.has_video().await? {
+        job.total_frames().await?;
+    } else {
+        job.duration().await?;
+    }
+    // lets the client offer a choice of audio and subtitle tracks
+    job.streams().await?;
     Ok(ApiResponse::Success(job))
 }
+
+#[post("/upload/{id}/{token}/subtitles")]
+pub async fn upload_subtitles(
+    path: web::Path<(Uuid, String)>,
+    mut payload: Multipart,
+) -> Result<impl Responder, UploadError> {
+    let (id, token) = path.into_inner();
+    {
+        let app_state = APP_STATE.lock().await;
+        let job = app_state.jobs.get(&id).ok_or(UploadError::JobNotFound)?;
+        if job.auth != token {
+            return Err(UploadError::InvalidToken);
+        }
+        if job.completed {
+            return Err(UploadError::JobCompleted);
+        }
+    }
+
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+
+        let Some(content_disposition) = field.content_disposition() else {
+            continue;
+        };
+        if content_disposition.get_name() != Some("file") {
+            continue;
+        }
+
+        let filename = content_disposition
+            .get_filename()
+            .ok_or_else(|| UploadError::NoFilename)?;
+        let ext = filename
+            .split('.')
+            .next_back()
+            .map(|ext| ext.to_lowercase())
+            .ok_or_else(|| UploadError::NoExtension)?;
+        if !SUBTITLE_FILE_EXTENSIONS.contains(&ext.as_str()) {
+            return Err(UploadError::InvalidExtension(
+                ext,
+                SUBTITLE_FILE_EXTENSIONS.join(", "),
+            ));
+        }
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+
+        let mut app_state = APP_STATE.lock().await;
+        let job = app_state
+            .jobs
+            .get_mut(&id)
+            .ok_or(UploadError::JobNotFound)?;
+        // a new upload replaces the last one, which might have had another extension
+        if let Some(old) = job.subtitle_path() {
+            fs::remove_file(old).await.ok();
+        }
+        job.subtitle_file = Some(ext);
+        let path = job.subtitle_path().unwrap_or_default();
+        fs::write(&path, &bytes).await?;
+        info!("uploaded subtitles for {}", id);
+
+        return Ok(ApiResponse::Success(job.clone()));
+    }
+
+    Err(UploadError::NoFile)
+}
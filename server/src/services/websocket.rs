@@ -10,7 +10,12 @@ use tokio::fs;
 use uuid::Uuid;
 
 use crate::{
-    converter::{format::ConverterFormat, job::ProgressUpdate, speed::ConversionSpeed, Converter},
+    converter::{
+        format::ConverterFormat,
+        job::{JobReport, ProgressUpdate},
+        options::ConversionOptions,
+        speed::ConversionSpeed, Converter,
+    },
     APP_STATE, OUTPUT_LIFETIME,
 };
 
@@ -23,10 +28,17 @@ pub enum Message {
         job_id: Uuid,
         to: String,
         speed: ConversionSpeed,
+        // boxed since it dwarfs the other messages
+        #[serde(flatten)]
+        options: Box<ConversionOptions>,
     },
 
     #[serde(rename = "jobFinished", rename_all = "camelCase")]
-    JobFinished { job_id: Uuid },
+    JobFinished {
+        job_id: Uuid,
+        #[serde(flatten)]
+        report: JobReport,
+    },
 
     #[serde(rename = "progressUpdate", rename_all = "camelCase")]
     ProgressUpdate(ProgressUpdate),
@@ -68,6 +80,7 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
                 job_id,
                 to,
                 speed,
+                options,
             } = message
             {
                 let Some(mut job) = ({
@@ -122,7 +135,7 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
                     continue;
                 };
 
-                let converter = Converter::new(from, to, speed);
+                let converter = Converter::new(from, to, speed, *options);
 
                 let mut rx = match converter.convert(&mut job).await {
                     Ok(rx) => rx,
@@ -157,12 +170,12 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
                 drop(app_state);
 
                 // check if output/{}.{} exists and isn't empty
-                let is_empty = fs::metadata(&format!("output/{}.{}", job_id, to))
+                let output_size = fs::metadata(&format!("output/{}.{}", job_id, to))
                     .await
-                    .map(|m| m.len() == 0)
-                    .unwrap_or(true);
+                    .map(|m| m.len())
+                    .unwrap_or(0);
 
-                if is_empty {
+                if output_size == 0 {
                     log::error!("job {} failed", job_id);
                     let message: String = Message::Error {
                         message: "oops -- your job failed! maddie has been notified :)".to_string(),
@@ -180,7 +193,11 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
                         }
                     });
                 } else {
-                    let message: String = Message::JobFinished { job_id }.into();
+                    let message: String = Message::JobFinished {
+                        job_id,
+                        report: converter.report(output_size),
+                    }
+                    .into();
                     session.text(message).await.unwrap();
                 }
 
@@ -191,13 +208,17 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
                     drop(app_state);
 
                     let path = format!("output/{}.{}", job_id, to);
-                    if let Err(e) = fs::remove_file(&path).await {
-                        if e.kind() != std::io::ErrorKind::NotFound {
-                            log::error!("failed to remove output file: {}", e);
-                        }
+                    if let Err(e) = fs::remove_file(&path).await
+                        && e.kind() != std::io::ErrorKind::NotFound
+                    {
+                        log::error!("failed to remove output file: {}", e);
                     }
                 });
 
+                if let Some(path) = job.subtitle_path() {
+                    fs::remove_file(path).await.ok();
+                }
+
                 match fs::remove_file(&format!("input/{}.{}", job.id, job.from)).await {
                     Ok(_) => {}
                     Err(e) => {
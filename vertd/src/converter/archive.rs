@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+// zips every file in `dir` (non-recursively, sorted by name) into `out`. the archive is
+// written next to it first, so a failure never leaves a half-written `out` behind
+pub fn zip_dir(dir: &Path, out: &Path) -> anyhow::Result<()> {
+    let partial = out.with_extension("zip.part");
+    let result = write_zip(dir, &partial).and_then(|()| Ok(std::fs::rename(&partial, out)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+// frames are already compressed images, so they're stored as-is rather than deflated again
+fn write_zip(dir: &Path, out: &Path) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let mut zip = ZipWriter::new(File::create(out)?);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+
+    for path in entries {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        zip.start_file(name, options)?;
+        io::copy(&mut File::open(&path)?, &mut zip)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
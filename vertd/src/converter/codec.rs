@@ -0,0 +1,330 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use tokio::process::Command;
+
+use super::{format::ConverterFormat, gpu::ConverterGPU};
+
+/// the raw `ffmpeg -encoders` listing for the ffmpeg build we're running against
+pub async fn available_encoders() -> anyhow::Result<String> {
+    let encoders = Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to get encoder support: {}", e))?;
+    Ok(String::from_utf8(encoders.stdout)?)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum VideoCodec {
+    H264,
+    HEVC,
+    AV1,
+    VP9,
+    VP8,
+    MPEG4,
+    WMV2,
+}
+
+impl VideoCodec {
+    /// the name ffprobe reports for streams of this codec, which is also what ffmpeg's
+    /// hardware encoders are prefixed with (e.g. hevc_nvenc)
+    pub fn codec_name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::HEVC => "hevc",
+            VideoCodec::AV1 => "av1",
+            VideoCodec::VP9 => "vp9",
+            VideoCodec::VP8 => "vp8",
+            VideoCodec::MPEG4 => "mpeg4",
+            VideoCodec::WMV2 => "wmv2",
+        }
+    }
+
+    /// roughly how many bits a pixel of each frame needs to look good, newer codecs get away
+    /// with a lot less
+    pub fn bits_per_pixel(&self) -> f64 {
+        match self {
+            VideoCodec::H264 => 0.1,
+            VideoCodec::HEVC => 0.065,
+            VideoCodec::AV1 => 0.05,
+            VideoCodec::VP9 => 0.065,
+            VideoCodec::VP8 => 0.11,
+            VideoCodec::MPEG4 => 0.15,
+            VideoCodec::WMV2 => 0.17,
+        }
+    }
+
+    /// software encoders in order of preference, the first is used if ffmpeg has none of them
+    pub fn software_encoders(&self) -> &'static [&'static str] {
+        match self {
+            VideoCodec::H264 => &["libx264"],
+            VideoCodec::HEVC => &["libx265"],
+            // svt-av1 is several times faster than libaom at similar quality
+            VideoCodec::AV1 => &["libsvtav1", "libaom-av1"],
+            VideoCodec::VP9 => &["libvpx-vp9"],
+            VideoCodec::VP8 => &["libvpx"],
+            VideoCodec::MPEG4 => &["mpeg4"],
+            VideoCodec::WMV2 => &["wmv2"],
+        }
+    }
+
+    /// picks the GPU encoder for this codec if there is one, otherwise the best software encoder
+    pub async fn encoder(&self, gpu: &ConverterGPU) -> String {
+        if let Ok(encoder) = gpu.get_accelerated_codec(self.codec_name()).await {
+            return encoder;
+        }
+
+        let software = self.software_encoders();
+        let available = available_encoders().await.unwrap_or_default();
+        software
+            .iter()
+            .find(|encoder| available.split_whitespace().any(|name| name == **encoder))
+            .unwrap_or(&software[0])
+            .to_string()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AudioCodec {
+    AAC,
+    MP3,
+    Opus,
+    Vorbis,
+    FLAC,
+    PCM,
+    AC3,
+    WMAv2,
+}
+
+impl AudioCodec {
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::AAC => "aac",
+            AudioCodec::MP3 => "libmp3lame",
+            AudioCodec::Opus => "libopus",
+            AudioCodec::Vorbis => "libvorbis",
+            AudioCodec::FLAC => "flac",
+            AudioCodec::PCM => "pcm_s16le",
+            AudioCodec::AC3 => "ac3",
+            AudioCodec::WMAv2 => "wmav2",
+        }
+    }
+
+    /// the name ffprobe reports for streams of this codec
+    pub fn codec_name(&self) -> &'static str {
+        match self {
+            AudioCodec::AAC => "aac",
+            AudioCodec::MP3 => "mp3",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Vorbis => "vorbis",
+            AudioCodec::FLAC => "flac",
+            AudioCodec::PCM => "pcm_s16le",
+            AudioCodec::AC3 => "ac3",
+            AudioCodec::WMAv2 => "wmav2",
+        }
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, AudioCodec::FLAC | AudioCodec::PCM)
+    }
+}
+
+/// the video codecs each container can be encoded to. the first one is the default,
+/// except for webm which prefers whatever the GPU can encode
+pub fn video_codecs(format: &ConverterFormat) -> &'static [VideoCodec] {
+    match format {
+        ConverterFormat::MP4 => &[
+            VideoCodec::H264,
+            VideoCodec::HEVC,
+            VideoCodec::AV1,
+            VideoCodec::VP9,
+            VideoCodec::MPEG4,
+        ],
+        ConverterFormat::MOV => &[VideoCodec::H264, VideoCodec::HEVC, VideoCodec::MPEG4],
+        ConverterFormat::MTS | ConverterFormat::TS | ConverterFormat::M2TS => {
+            &[VideoCodec::H264, VideoCodec::HEVC]
+        }
+        ConverterFormat::MKV => &[
+            VideoCodec::H264,
+            VideoCodec::HEVC,
+            VideoCodec::AV1,
+            VideoCodec::VP9,
+            VideoCodec::VP8,
+            VideoCodec::MPEG4,
+        ],
+        ConverterFormat::WebM => &[VideoCodec::VP8, VideoCodec::VP9, VideoCodec::AV1],
+        ConverterFormat::AVI => &[VideoCodec::MPEG4, VideoCodec::H264],
+        ConverterFormat::WMV => &[VideoCodec::WMV2],
+        ConverterFormat::GIF
+        | ConverterFormat::WebP
+        | ConverterFormat::APNG
+        | ConverterFormat::ZIP
+        | ConverterFormat::MP3
+        | ConverterFormat::M4A
+        | ConverterFormat::FLAC
+        | ConverterFormat::WAV
+        | ConverterFormat::OGG
+        | ConverterFormat::Opus => &[],
+    }
+}
+
+/// the audio codecs each container can be encoded to, the first one being the default
+pub fn audio_codecs(format: &ConverterFormat) -> &'static [AudioCodec] {
+    match format {
+        ConverterFormat::MP4 => &[
+            AudioCodec::AAC,
+            AudioCodec::MP3,
+            AudioCodec::Opus,
+            AudioCodec::AC3,
+        ],
+        ConverterFormat::MOV => &[
+            AudioCodec::AAC,
+            AudioCodec::MP3,
+            AudioCodec::PCM,
+            AudioCodec::AC3,
+        ],
+        ConverterFormat::MKV => &[
+            AudioCodec::AAC,
+            AudioCodec::MP3,
+            AudioCodec::Opus,
+            AudioCodec::Vorbis,
+            AudioCodec::FLAC,
+            AudioCodec::PCM,
+            AudioCodec::AC3,
+        ],
+        ConverterFormat::MTS | ConverterFormat::TS | ConverterFormat::M2TS => {
+            &[AudioCodec::AAC, AudioCodec::MP3, AudioCodec::AC3]
+        }
+        ConverterFormat::WebM => &[AudioCodec::Vorbis, AudioCodec::Opus],
+        ConverterFormat::AVI => &[AudioCodec::MP3, AudioCodec::AC3, AudioCodec::PCM],
+        ConverterFormat::WMV => &[AudioCodec::WMAv2],
+        ConverterFormat::MP3 => &[AudioCodec::MP3],
+        ConverterFormat::M4A => &[AudioCodec::AAC],
+        ConverterFormat::FLAC => &[AudioCodec::FLAC],
+        ConverterFormat::WAV => &[AudioCodec::PCM],
+        ConverterFormat::OGG => &[AudioCodec::Vorbis, AudioCodec::Opus, AudioCodec::FLAC],
+        ConverterFormat::Opus => &[AudioCodec::Opus],
+        ConverterFormat::GIF
+        | ConverterFormat::WebP
+        | ConverterFormat::APNG
+        | ConverterFormat::ZIP => &[],
+    }
+}
+
+// picture-based subtitles, which can't be turned into text
+const BITMAP_SUBTITLES: [&str; 4] = ["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"];
+
+pub fn is_bitmap_subtitle(codec: &str) -> bool {
+    BITMAP_SUBTITLES.contains(&codec)
+}
+
+/// what a subtitle stream in `codec` gets written as in `format`, or None if it can't be
+pub fn subtitle_encoder(format: &ConverterFormat, codec: &str) -> Option<&'static str> {
+    let bitmap = is_bitmap_subtitle(codec);
+    match format {
+        ConverterFormat::MP4 | ConverterFormat::MOV if !bitmap => Some("mov_text"),
+        ConverterFormat::WebM if !bitmap => Some("webvtt"),
+        // matroska takes just about anything, except mp4's own text format
+        ConverterFormat::MKV if codec == "mov_text" => Some("srt"),
+        ConverterFormat::MKV => Some("copy"),
+        _ => None,
+    }
+}
+
+/// checks a job's codec choices against the tables above
+pub fn validate(
+    format: &ConverterFormat,
+    video: Option<VideoCodec>,
+    audio: Option<AudioCodec>,
+) -> anyhow::Result<()> {
+    if let Some(codec) = video
+        && !video_codecs(format).contains(&codec)
+    {
+        return Err(anyhow!(
+            "{} video can't be written to {} (allowed: {})",
+            codec,
+            format,
+            allowed(video_codecs(format))
+        ));
+    }
+
+    if let Some(codec) = audio
+        && !audio_codecs(format).contains(&codec)
+    {
+        return Err(anyhow!(
+            "{} audio can't be written to {} (allowed: {})",
+            codec,
+            format,
+            allowed(audio_codecs(format))
+        ));
+    }
+
+    Ok(())
+}
+
+fn allowed<T: ToString>(codecs: &[T]) -> String {
+    if codecs.is_empty() {
+        return "none".to_string();
+    }
+    codecs
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// the codecs (as ffprobe names them) each container can take as-is, as (video, audio).
+/// formats without an entry always get re-encoded
+pub fn remux_codecs(
+    format: &ConverterFormat,
+) -> Option<(&'static [&'static str], &'static [&'static str])> {
+    match format {
+        ConverterFormat::MP4 => Some((
+            &["h264", "hevc", "av1", "vp9", "mpeg4"],
+            &["aac", "mp3", "ac3", "eac3", "alac", "opus"],
+        )),
+        ConverterFormat::MOV => Some((
+            &["h264", "hevc", "mpeg4", "prores", "mjpeg"],
+            &["aac", "mp3", "ac3", "alac", "pcm_s16le", "pcm_s24le"],
+        )),
+        ConverterFormat::MKV => Some((
+            &["h264", "hevc", "av1", "vp9", "vp8", "mpeg4", "mpeg2video"],
+            &[
+                "aac",
+                "mp3",
+                "ac3",
+                "eac3",
+                "dts",
+                "flac",
+                "opus",
+                "vorbis",
+                "alac",
+                "pcm_s16le",
+                "pcm_s24le",
+                "truehd",
+            ],
+        )),
+        ConverterFormat::WebM => Some((&["vp8", "vp9", "av1"], &["vorbis", "opus"])),
+        ConverterFormat::MTS | ConverterFormat::TS | ConverterFormat::M2TS => Some((
+            &["h264", "hevc", "mpeg2video"],
+            &["aac", "mp3", "mp2", "ac3", "eac3"],
+        )),
+        ConverterFormat::AVI => Some((&["mpeg4", "h264", "mjpeg"], &["mp3", "ac3", "pcm_s16le"])),
+        ConverterFormat::WMV => Some((&["wmv2", "wmv3"], &["wmav2"])),
+        ConverterFormat::MP3 => Some((&[], &["mp3"])),
+        ConverterFormat::M4A => Some((&[], &["aac", "alac"])),
+        ConverterFormat::FLAC => Some((&[], &["flac"])),
+        ConverterFormat::WAV => Some((&[], &["pcm_s16le"])),
+        ConverterFormat::OGG => Some((&[], &["vorbis", "opus", "flac"])),
+        ConverterFormat::Opus => Some((&[], &["opus"])),
+        ConverterFormat::GIF
+        | ConverterFormat::WebP
+        | ConverterFormat::APNG
+        | ConverterFormat::ZIP => None,
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::anyhow;
+use log::warn;
+use tokio::process::Command;
+
+pub async fn available_filters() -> anyhow::Result<String> {
+    let filters = Command::new("ffmpeg")
+        .args(["-hide_banner", "-filters"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to get filter support: {}", e))?;
+    Ok(String::from_utf8(filters.stdout)?)
+}
+
+/// whether this ffmpeg build has the filter called `name`
+pub async fn is_available(name: &str) -> bool {
+    available_filters()
+        .await
+        .unwrap_or_default()
+        .split_whitespace()
+        .any(|filter| filter == name)
+}
+
+/// a `-vf` chain, built up one filter at a time so the options can compose
+#[derive(Debug, Default)]
+pub struct FilterChain {
+    filters: Vec<String>,
+    // global args some hardware filters need to find their device
+    device_args: Vec<String>,
+    // hdr that isn't being tonemapped has to stay 10-bit on its way to the gpu
+    ten_bit: bool,
+}
+
+impl FilterChain {
+    pub fn push(&mut self, filter: impl Into<String>) {
+        self.filters.push(filter.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// uploads frames for hardware filters as p010 rather than 8-bit nv12
+    pub fn keep_ten_bit(&mut self) {
+        self.ten_bit = true;
+    }
+
+    /// adds a resize, on the gpu when `scaler` is a hardware one
+    pub fn push_scale(&mut self, scale: &Scale, scaler: Scaler) {
+        let upload_format = if self.ten_bit {
+            "format=p010"
+        } else {
+            "format=nv12"
+        };
+        match scaler {
+            Scaler::Software => self.push(format!(
+                "scale=w={}:h={}:flags=lanczos",
+                scale.width, scale.height
+            )),
+            // the decoder hands us frames in system memory, so they have to be
+            // uploaded before the gpu can scale them
+            Scaler::Cuda => {
+                self.push(upload_format);
+                self.push("hwupload_cuda");
+                self.push(format!("scale_cuda=w={}:h={}", scale.width, scale.height));
+            }
+            Scaler::Qsv => {
+                self.device_args = ["-init_hw_device", "qsv=hw", "-filter_hw_device", "hw"]
+                    .map(String::from)
+                    .to_vec();
+                self.push(upload_format);
+                self.push("hwupload=extra_hw_frames=64");
+                self.push(format!("scale_qsv=w={}:h={}", scale.width, scale.height));
+            }
+        }
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        if self.filters.is_empty() {
+            return Vec::new();
+        }
+
+        let mut args = self.device_args.clone();
+        args.push("-vf".to_string());
+        args.push(self.filters.join(","));
+        args
+    }
+}
+
+/// output dimensions as ffmpeg expressions, so they can depend on the input size
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub width: String,
+    pub height: String,
+}
+
+/// where a resize runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaler {
+    Software,
+    Cuda,
+    Qsv,
+}
+
+impl Scaler {
+    /// scale on the gpu that's going to encode the frames anyway, if there is one and
+    /// ffmpeg was built with its scaler
+    pub async fn for_encoder(encoder: Option<&str>) -> Scaler {
+        let (scaler, filter) = match encoder {
+            Some(encoder) if encoder.ends_with("_nvenc") => (Scaler::Cuda, "scale_cuda"),
+            Some(encoder) if encoder.ends_with("_qsv") => (Scaler::Qsv, "scale_qsv"),
+            // amf and videotoolbox scalers only exist in very new ffmpeg builds
+            _ => return Scaler::Software,
+        };
+
+        if is_available(filter).await {
+            scaler
+        } else {
+            warn!("ffmpeg was built without {}, scaling on the cpu", filter);
+            Scaler::Software
+        }
+    }
+}
+
+/// 4:2:0 h264/hevc can't encode odd widths or heights
+pub fn needs_even_dimensions(encoder: Option<&str>) -> bool {
+    encoder.is_some_and(|encoder| {
+        ["libx264", "libx265", "h264_", "hevc_"]
+            .iter()
+            .any(|prefix| encoder.starts_with(prefix))
+    })
+}
+
+/// whether `encoder` writes the 10-bit output hdr needs to survive
+pub fn carries_hdr(encoder: Option<&str>) -> bool {
+    encoder.is_some_and(|encoder| {
+        [
+            "libx265",
+            "hevc_",
+            "libsvtav1",
+            "libaom-av1",
+            "av1_",
+            "libvpx-vp9",
+            "vp9_",
+        ]
+        .iter()
+        .any(|prefix| encoder.starts_with(prefix))
+    })
+}
@@ -1,7 +1,19 @@
-use super::{gpu::ConverterGPU, speed::ConversionSpeed};
-use strum_macros::{Display, EnumString};
+use super::{
+    codec::{self, AudioCodec, VideoCodec},
+    filter::{self, FilterChain, Scaler},
+    gpu::ConverterGPU,
+    job::StreamInfo,
+    options::{
+        AudioTracks, BurnInSource, ConversionOptions, RateControl, ResizeOptions, TonemapMode,
+    },
+    speed::ConversionSpeed,
+};
+use log::warn;
+use serde::Serialize;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
 
-#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display)]
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, EnumIter)]
 #[strum(serialize_all = "lowercase")]
 pub enum ConverterFormat {
     MP4,
@@ -14,16 +26,210 @@ pub enum ConverterFormat {
     MTS,
     TS,
     M2TS,
+    MP3,
+    M4A,
+    FLAC,
+    WAV,
+    OGG,
+    Opus,
+    WebP,
+    APNG,
+    // a zip of still frames, see `ImageSequenceOptions`
+    ZIP,
 }
 
 impl ConverterFormat {
+    /// whether this format only carries an audio stream
+    pub fn is_audio(&self) -> bool {
+        matches!(
+            self,
+            ConverterFormat::MP3
+                | ConverterFormat::M4A
+                | ConverterFormat::FLAC
+                | ConverterFormat::WAV
+                | ConverterFormat::OGG
+                | ConverterFormat::Opus
+        )
+    }
+
+    /// whether this format is an animated image, which carries no audio or bitrate target
+    pub fn is_animation(&self) -> bool {
+        matches!(
+            self,
+            ConverterFormat::GIF | ConverterFormat::WebP | ConverterFormat::APNG
+        )
+    }
+
+    /// whether this format is a regular video container
+    pub fn is_video(&self) -> bool {
+        !self.is_audio() && !self.is_animation() && *self != ConverterFormat::ZIP
+    }
+
+    /// whether this format can carry an audio stream
+    pub fn has_audio(&self) -> bool {
+        !self.is_animation() && *self != ConverterFormat::ZIP
+    }
+
+    /// whether the container can hold more than one audio track
+    pub fn multiple_audio_tracks(&self) -> bool {
+        self.is_video()
+    }
+
+    /// whether this format can carry transparency with the encoders we use
+    pub fn has_alpha(&self) -> bool {
+        self.is_animation() || *self == ConverterFormat::ZIP
+    }
+
+    /// whether files in this format can be uploaded for conversion
+    pub fn is_input(&self) -> bool {
+        // ffmpeg's webp decoder can't do animated files (as of 6.1, what the docker image
+        // ships), which are the only kind worth uploading here
+        !matches!(self, ConverterFormat::ZIP | ConverterFormat::WebP)
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ConverterFormat::MP4 => "video/mp4",
+            ConverterFormat::WebM => "video/webm",
+            ConverterFormat::GIF => "image/gif",
+            ConverterFormat::AVI => "video/x-msvideo",
+            ConverterFormat::MKV => "video/x-matroska",
+            ConverterFormat::WMV => "video/x-ms-wmv",
+            ConverterFormat::MOV => "video/quicktime",
+            ConverterFormat::MTS | ConverterFormat::TS | ConverterFormat::M2TS => "video/mp2t",
+            ConverterFormat::MP3 => "audio/mpeg",
+            ConverterFormat::M4A => "audio/mp4",
+            ConverterFormat::FLAC => "audio/flac",
+            ConverterFormat::WAV => "audio/wav",
+            ConverterFormat::OGG => "audio/ogg",
+            ConverterFormat::Opus => "audio/opus",
+            ConverterFormat::WebP => "image/webp",
+            ConverterFormat::APNG => "image/apng",
+            ConverterFormat::ZIP => "application/zip",
+        }
+    }
+
+    /// every format that can be uploaded, as a human-readable list
+    pub fn input_list() -> String {
+        ConverterFormat::iter()
+            .filter(|f| f.is_input())
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     pub fn conversion_into_args(
         &self,
         speed: &ConversionSpeed,
-        gpu: &ConverterGPU,
+        encoder: Option<&str>,
+        rate_control: &RateControl,
         bitrate: u64,
     ) -> Vec<String> {
-        speed.to_args(self, gpu, bitrate)
+        speed.to_args(self, encoder, rate_control, bitrate)
+    }
+}
+
+/// everything a client needs to know to offer a format, see `FormatInfo::all`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatInfo {
+    pub name: String,
+    pub mime: &'static str,
+    pub input: bool,
+    pub output: bool,
+    pub video: bool,
+    pub audio: bool,
+    pub alpha: bool,
+    pub video_codecs: &'static [VideoCodec],
+    pub audio_codecs: &'static [AudioCodec],
+    /// the `ConversionOptions` fields that have an effect when converting to this format
+    pub options: Vec<&'static str>,
+}
+
+impl FormatInfo {
+    pub fn new(format: ConverterFormat) -> Self {
+        Self {
+            name: format.to_string(),
+            mime: format.mime(),
+            input: format.is_input(),
+            // every format we know about can be written
+            output: true,
+            video: !format.is_audio(),
+            audio: format.has_audio(),
+            alpha: format.has_alpha(),
+            video_codecs: codec::video_codecs(&format),
+            audio_codecs: codec::audio_codecs(&format),
+            options: ConversionOptions::applicable_to(&format),
+        }
+    }
+
+    pub fn all() -> Vec<Self> {
+        ConverterFormat::iter().map(Self::new).collect()
+    }
+}
+
+/// what's been probed from, or prepared for, the input of `Conversion::to_args`
+pub struct InputInfo {
+    pub bitrate: u64,
+    pub fps: u32,
+    /// see `StreamInfo::is_hdr`
+    pub hdr: bool,
+    /// a `subtitles` filter to render into the picture
+    pub burn_in: Option<String>,
+}
+
+/// where an audio track of the output comes from
+#[derive(Debug, Clone)]
+pub enum AudioSource<'a> {
+    Stream(&'a StreamInfo),
+    /// these streams mixed down into one
+    Mix(Vec<&'a StreamInfo>),
+}
+
+impl AudioSource<'_> {
+    /// args that make this the `n`th audio track of the output, with `filters` run over it
+    pub fn map_args(&self, n: usize, filters: &[String]) -> Vec<String> {
+        match self {
+            AudioSource::Stream(stream) => {
+                let mut args = vec!["-map".to_string(), format!("0:{}", stream.index)];
+                if !filters.is_empty() {
+                    args.extend([format!("-filter:a:{}", n), filters.join(",")]);
+                }
+                args
+            }
+            AudioSource::Mix(streams) => {
+                let inputs = streams
+                    .iter()
+                    .map(|s| format!("[0:{}]", s.index))
+                    .collect::<String>();
+                // without normalize every track gets quieter the more there are
+                let mut graph = format!(
+                    "{}amix=inputs={}:duration=longest:normalize=0",
+                    inputs,
+                    streams.len()
+                );
+                for filter in filters {
+                    graph.push(',');
+                    graph.push_str(filter);
+                }
+                graph.push_str("[mix]");
+                vec![
+                    "-filter_complex".to_string(),
+                    graph,
+                    "-map".to_string(),
+                    "[mix]".to_string(),
+                ]
+            }
+        }
+    }
+
+    /// in hz, of the (first) source stream
+    pub fn sample_rate(&self) -> Option<u32> {
+        let stream = match self {
+            AudioSource::Stream(stream) => stream,
+            AudioSource::Mix(streams) => streams.first()?,
+        };
+        stream.sample_rate.as_deref()?.parse().ok()
     }
 }
 
@@ -37,87 +243,428 @@ impl Conversion {
         Self { from, to }
     }
 
-    async fn accelerated_or_default_codec(
+    /// picks the streams that go into the output. ffmpeg's own pick takes at most one of
+    /// each kind and trips over subtitles the container can't hold. `audio_filters` are
+    /// run over the matching track of `audio_sources`
+    pub fn map_args(
         &self,
-        gpu: &ConverterGPU,
-        codecs: &[&str],
-        default: &str,
-    ) -> String {
-        for codec in codecs {
-            if let Ok(encoder) = gpu.get_accelerated_codec(codec).await {
-                return encoder;
+        streams: &[StreamInfo],
+        options: &ConversionOptions,
+        audio_filters: &[Vec<String>],
+    ) -> Vec<String> {
+        let metadata = &options.metadata;
+        let mut args = metadata.to_args(&self.to);
+        if !self.to.is_audio() {
+            // capital V leaves out cover art
+            args.extend(["-map".to_string(), "0:V:0".to_string()]);
+            if let Some(stream) = streams.iter().find(|s| s.is_video()) {
+                args.extend(metadata.stream_args('v', 0, stream));
+            }
+        }
+
+        for (n, source) in self.audio_sources(streams, options).iter().enumerate() {
+            let filters = audio_filters.get(n).map(Vec::as_slice).unwrap_or_default();
+            args.extend(source.map_args(n, filters));
+            if let AudioSource::Stream(stream) = source {
+                args.extend(metadata.stream_args('a', n, stream));
+            }
+        }
+
+        let mut kept = 0;
+        for stream in streams.iter().filter(|s| s.is_subtitle()) {
+            // a burned in track is already on screen, it doesn't need showing twice
+            let burned_in = BurnInSource::Stream {
+                index: stream.index,
+            };
+            if !options.subtitles.includes(stream) || options.burn_in.source == Some(burned_in) {
+                continue;
+            }
+            let codec = stream.codec_name.as_deref().unwrap_or("unknown");
+            let Some(encoder) = codec::subtitle_encoder(&self.to, codec) else {
+                // audio-only and animated targets obviously can't, no need to say so
+                if self.to.is_video() {
+                    warn!(
+                        "dropping {} subtitle stream {}, {} can't hold it",
+                        codec, stream.index, self.to
+                    );
+                }
+                continue;
+            };
+
+            args.extend([
+                "-map".to_string(),
+                format!("0:{}", stream.index),
+                format!("-c:s:{}", kept),
+                encoder.to_string(),
+            ]);
+            args.extend(metadata.stream_args('s', kept, stream));
+            kept += 1;
+        }
+        args
+    }
+
+    /// where each audio track of the output comes from, in order
+    pub fn audio_sources<'a>(
+        &self,
+        streams: &'a [StreamInfo],
+        options: &ConversionOptions,
+    ) -> Vec<AudioSource<'a>> {
+        if !self.to.has_audio() || options.audio.strip {
+            return Vec::new();
+        }
+
+        let tracks = &options.audio_tracks;
+        let audio = streams.iter().filter(|s| s.is_audio()).collect::<Vec<_>>();
+
+        if *tracks == AudioTracks::Mix && audio.len() > 1 {
+            return vec![AudioSource::Mix(audio)];
+        }
+
+        let mut selected = audio
+            .into_iter()
+            .filter(|s| tracks.includes(s))
+            .collect::<Vec<_>>();
+        if selected.is_empty() && matches!(tracks, AudioTracks::Select { .. }) {
+            warn!("no audio tracks matched the selection, output will be silent");
+        }
+        if selected.len() > 1 && !self.to.multiple_audio_tracks() {
+            let keep = selected
+                .iter()
+                .find(|s| s.disposition.default == 1)
+                .unwrap_or(&selected[0]);
+            warn!(
+                "{} only holds one audio track, keeping stream {} of {}",
+                self.to,
+                keep.index,
+                selected.len()
+            );
+            selected = vec![*keep];
+        }
+
+        selected.into_iter().map(AudioSource::Stream).collect()
+    }
+
+    /// stream copy args for when every stream already fits the target container, so
+    /// nothing needs re-encoding. returns None if anything does
+    pub fn remux_args(
+        &self,
+        streams: &[StreamInfo],
+        options: &ConversionOptions,
+    ) -> Option<Vec<String>> {
+        // invalid codec choices fall through to to_args, which reports them
+        if options.requires_reencode()
+            || codec::validate(&self.to, options.video_codec, options.audio_codec).is_err()
+        {
+            return None;
+        }
+
+        // forced tonemapping needs the video decoded
+        if options.tonemap.mode == TonemapMode::Always
+            && streams.iter().any(|s| s.is_video() && s.is_hdr())
+        {
+            return None;
+        }
+
+        let (video_codecs, audio_codecs) = codec::remux_codecs(&self.to)?;
+        let fits = |stream: &StreamInfo, codecs: &[&str]| {
+            stream
+                .codec_name
+                .as_deref()
+                .is_some_and(|name| codecs.contains(&name))
+        };
+
+        let video = streams.iter().filter(|s| s.is_video()).collect::<Vec<_>>();
+        // only the tracks that are kept have to fit, stripped and unselected ones don't
+        let audio = self
+            .audio_sources(streams, options)
+            .into_iter()
+            .flat_map(|source| match source {
+                AudioSource::Stream(stream) => vec![stream],
+                AudioSource::Mix(streams) => streams,
+            })
+            .collect::<Vec<_>>();
+
+        if !audio.iter().all(|s| fits(s, audio_codecs)) {
+            return None;
+        }
+        if let Some(codec) = options.audio_codec
+            && audio
+                .iter()
+                .any(|s| s.codec_name.as_deref() != Some(codec.codec_name()))
+        {
+            return None;
+        }
+
+        let mut args = Vec::new();
+        if self.to.is_audio() {
+            if audio.is_empty() {
+                return None;
+            }
+            args.push("-vn".to_string());
+        } else {
+            if video.is_empty() || !video.iter().all(|s| fits(s, video_codecs)) {
+                return None;
             }
+            if let Some(codec) = options.video_codec
+                && video
+                    .iter()
+                    .any(|s| s.codec_name.as_deref() != Some(codec.codec_name()))
+            {
+                return None;
+            }
+
+            args.push("-c:v".to_string());
+            args.push("copy".to_string());
+            if matches!(self.to, ConverterFormat::MP4 | ConverterFormat::MOV)
+                && video
+                    .iter()
+                    .any(|s| s.codec_name.as_deref() == Some("hevc"))
+            {
+                args.push("-tag:v".to_string());
+                args.push("hvc1".to_string());
+            }
+        }
+
+        if options.audio.strip {
+            args.push("-an".to_string());
+        } else {
+            args.push("-c:a".to_string());
+            args.push("copy".to_string());
+        }
+        Some(args)
+    }
+
+    /// the codec the video is going to end up in, as far as we can tell before an encoder
+    /// has been picked
+    pub async fn video_codec(&self, gpu: &ConverterGPU, options: &ConversionOptions) -> VideoCodec {
+        if let Some(codec) = options.video_codec {
+            return codec;
+        }
+
+        match self.to {
+            // webm goes for whichever of its codecs the gpu can encode, best first
+            ConverterFormat::WebM => {
+                for codec in [VideoCodec::AV1, VideoCodec::VP9] {
+                    if gpu.get_accelerated_codec(codec.codec_name()).await.is_ok() {
+                        return codec;
+                    }
+                }
+                VideoCodec::VP8
+            }
+            to => codec::video_codecs(&to)
+                .first()
+                .copied()
+                .unwrap_or(VideoCodec::H264),
         }
-        default.to_string()
     }
 
     pub async fn to_args(
         &self,
         speed: &ConversionSpeed,
         gpu: &ConverterGPU,
-        bitrate: u64,
-        fps: u32,
+        options: &ConversionOptions,
+        input: InputInfo,
     ) -> anyhow::Result<Vec<String>> {
-        let conversion_opts: Vec<String> = match self.to {
+        let InputInfo {
+            bitrate,
+            fps,
+            hdr,
+            burn_in,
+        } = input;
+
+        codec::validate(&self.to, options.video_codec, options.audio_codec)?;
+        options.rate_control.validate(&self.to)?;
+
+        // the speed preset depends on which encoder we end up with
+        let mut video_encoder = None;
+        let mut filters = FilterChain::default();
+
+        let mut conversion_opts: Vec<String> = match self.to {
             ConverterFormat::MP4
             | ConverterFormat::MKV
             | ConverterFormat::MOV
             | ConverterFormat::MTS
             | ConverterFormat::TS
             | ConverterFormat::M2TS => {
-                let encoder = self
-                    .accelerated_or_default_codec(gpu, &["h264"], "libx264")
-                    .await;
-                vec![
-                    "-c:v".to_string(),
-                    encoder,
-                    "-c:a".to_string(),
-                    "aac".to_string(),
-                    "-strict".to_string(),
-                    "experimental".to_string(),
-                ]
+                let codec = options.video_codec.unwrap_or(VideoCodec::H264);
+                let encoder = codec.encoder(gpu).await;
+                video_encoder = Some(encoder.clone());
+                let mut args = vec!["-c:v".to_string(), encoder];
+                // quicktime and safari only play hevc tagged as hvc1, ffmpeg defaults to hev1
+                if codec == VideoCodec::HEVC
+                    && matches!(self.to, ConverterFormat::MP4 | ConverterFormat::MOV)
+                {
+                    args.push("-tag:v".to_string());
+                    args.push("hvc1".to_string());
+                }
+                args
             }
 
             ConverterFormat::GIF => {
+                filters.push(options.gif.fps_filter(fps)?);
+                options.gif.output_args()
+            }
+
+            ConverterFormat::WebP => {
+                let animation = &options.animation;
+                filters.push(format!("fps={}", animation.output_fps(fps, 30)?));
+                let mut args = vec![
+                    "-c:v".to_string(),
+                    "libwebp_anim".to_string(),
+                    "-pix_fmt".to_string(),
+                    "yuva420p".to_string(),
+                ];
+                match animation.quality()? {
+                    Some(100) => args.extend(["-lossless".to_string(), "1".to_string()]),
+                    quality => args.extend([
+                        "-lossless".to_string(),
+                        "0".to_string(),
+                        "-quality".to_string(),
+                        quality.unwrap_or(75).to_string(),
+                    ]),
+                }
+                args.extend([
+                    "-loop".to_string(),
+                    animation.loop_count.unwrap_or(0).to_string(),
+                    "-an".to_string(),
+                ]);
+                args
+            }
+
+            ConverterFormat::APNG => {
+                let animation = &options.animation;
+                if animation.quality()?.is_some() {
+                    warn!("apng is lossless, ignoring animation quality");
+                }
+                filters.push(format!("fps={}", animation.output_fps(fps, 30)?));
                 vec![
-                   "-filter_complex".to_string(), 
-                   format!(
-                    "fps={},scale=800:-1:flags=lanczos,split[s0][s1];[s0]palettegen=max_colors=64[p];[s1][p]paletteuse=dither=bayer",
-                    fps.min(24)
-                   )
+                    "-c:v".to_string(),
+                    "apng".to_string(),
+                    "-pix_fmt".to_string(),
+                    "rgba".to_string(),
+                    "-pred".to_string(),
+                    "mixed".to_string(),
+                    "-plays".to_string(),
+                    animation.loop_count.unwrap_or(0).to_string(),
+                    "-an".to_string(),
                 ]
             }
 
+            ConverterFormat::ZIP => options.image_sequence.to_args(&mut filters)?,
+
             ConverterFormat::WMV => {
-                let encoder = self
-                    .accelerated_or_default_codec(gpu, &["wmv2", "wmv3"], "wmv2")
+                let encoder = options
+                    .video_codec
+                    .unwrap_or(VideoCodec::WMV2)
+                    .encoder(gpu)
                     .await;
-                vec![
-                    "-c:v".to_string(),
-                    encoder,
-                    "-c:a".to_string(),
-                    "wmav2".to_string(),
-                ]
+                video_encoder = Some(encoder.clone());
+                vec!["-c:v".to_string(), encoder]
             }
             ConverterFormat::WebM => {
-                let encoder = self
-                    .accelerated_or_default_codec(gpu, &["av1", "vp9", "vp8"], "libvpx")
+                let encoder = self.video_codec(gpu, options).await.encoder(gpu).await;
+                video_encoder = Some(encoder.clone());
+                vec!["-c:v".to_string(), encoder]
+            }
+            ConverterFormat::AVI => {
+                let encoder = options
+                    .video_codec
+                    .unwrap_or(VideoCodec::MPEG4)
+                    .encoder(gpu)
                     .await;
-                vec![
-                    "-c:v".to_string(),
-                    encoder.to_string(),
-                    "-c:a".to_string(),
-                    "libvorbis".to_string(),
-                ]
+                video_encoder = Some(encoder.clone());
+                vec!["-c:v".to_string(), encoder]
             }
-            ConverterFormat::AVI => vec![
-                "-c:v".to_string(),
-                "mpeg4".to_string(),
-                "-c:a".to_string(),
-                "libmp3lame".to_string(),
-            ],
+
+            // audio-only targets drop the video stream (and any cover art) entirely
+            ConverterFormat::MP3
+            | ConverterFormat::M4A
+            | ConverterFormat::FLAC
+            | ConverterFormat::WAV
+            | ConverterFormat::OGG
+            | ConverterFormat::Opus => vec!["-vn".to_string()],
         };
 
+        let mut tonemapped = false;
+        if hdr
+            && !self.to.is_audio()
+            && options
+                .tonemap
+                .wanted(filter::carries_hdr(video_encoder.as_deref()))
+        {
+            if filter::is_available("zscale").await {
+                for tonemap in options.tonemap.filters() {
+                    filters.push(tonemap);
+                }
+                tonemapped = true;
+                if self.to.is_video() {
+                    // tag the output so players don't take it for hdr still
+                    for (flag, value) in [
+                        ("-color_primaries", "bt709"),
+                        ("-color_trc", "bt709"),
+                        ("-colorspace", "bt709"),
+                    ] {
+                        conversion_opts.extend([flag.to_string(), value.to_string()]);
+                    }
+                }
+            } else {
+                warn!("ffmpeg was built without zscale, hdr input can't be tonemapped");
+            }
+        }
+        // an 8-bit encoder couldn't take 10-bit frames anyway
+        if hdr && !tonemapped && filter::carries_hdr(video_encoder.as_deref()) {
+            filters.keep_ten_bit();
+        }
+
+        if !self.to.is_audio() {
+            options.transform.apply(&mut filters)?;
+            if self.to.is_video() {
+                options.frame_rate.apply(&mut filters)?;
+            }
+            if let Some(burn_in) = burn_in {
+                filters.push(burn_in);
+            }
+            let resize = match self.to {
+                ConverterFormat::GIF if options.resize.is_empty() => ResizeOptions {
+                    max_width: Some(options.gif.width()?),
+                    ..Default::default()
+                },
+                _ => options.resize.clone(),
+            };
+            // no need to go looking for a gpu scaler when nothing's being scaled
+            let scaler = if resize.is_empty() {
+                Scaler::Software
+            } else {
+                Scaler::for_encoder(video_encoder.as_deref()).await
+            };
+            resize.apply(
+                &mut filters,
+                filter::needs_even_dimensions(video_encoder.as_deref()),
+                scaler,
+            )?;
+        }
+        if self.to == ConverterFormat::GIF {
+            filters.push(options.gif.palette_filter()?);
+        }
+        conversion_opts.extend(filters.to_args());
+
+        if self.to.has_audio() && options.audio.strip {
+            conversion_opts.push("-an".to_string());
+        } else if self.to.has_audio() {
+            // validated above, so there's always a default to fall back on
+            let audio_codec = options
+                .audio_codec
+                .unwrap_or(codec::audio_codecs(&self.to)[0]);
+            conversion_opts.push("-c:a".to_string());
+            conversion_opts.push(audio_codec.encoder().to_string());
+            // older ffmpeg builds still gate their native aac (and opus in mp4) behind this
+            if matches!(audio_codec, AudioCodec::AAC | AudioCodec::Opus) {
+                conversion_opts.push("-strict".to_string());
+                conversion_opts.push("experimental".to_string());
+            }
+            conversion_opts.extend(options.audio.to_args(&self.to, &audio_codec)?);
+        }
+
         let conversion_opts = conversion_opts
             .iter()
             .map(|s| s.to_string())
@@ -125,7 +672,12 @@ impl Conversion {
 
         let result = [
             conversion_opts,
-            self.to.conversion_into_args(speed, gpu, bitrate),
+            self.to.conversion_into_args(
+                speed,
+                video_encoder.as_deref(),
+                &options.rate_control,
+                bitrate,
+            ),
         ]
         .concat();
 
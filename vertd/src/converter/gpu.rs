@@ -1,9 +1,10 @@
 use anyhow::anyhow;
 use log::warn;
 use std::fmt::{self, Display, Formatter};
-use tokio::process::Command;
 use wgpu::Instance;
 
+use super::codec::available_encoders;
+
 pub enum ConverterGPU {
     AMD,
     Intel,
@@ -14,12 +15,7 @@ pub enum ConverterGPU {
 impl ConverterGPU {
     pub async fn get_accelerated_codec(&self, codec: &str) -> anyhow::Result<String> {
         let priority = self.encoder_priority();
-        let encoders = Command::new("ffmpeg")
-            .args(["-hide_banner", "-encoders"])
-            .output()
-            .await
-            .map_err(|e| anyhow!("failed to get encoder support: {}", e))?;
-        let encoders = String::from_utf8(encoders.stdout)?;
+        let encoders = available_encoders().await?;
         for encoder in priority {
             let encoder = format!("{}_{}", codec, encoder);
             if encoders.contains(&encoder) {
@@ -78,7 +74,9 @@ pub async fn get_gpu() -> anyhow::Result<ConverterGPU> {
         0x8086 => Ok(ConverterGPU::Intel), // fun fact: intel's vendor id is 0x8086, presumably in reference to the intel 8086 processor
         0x106B | 0x0 => Ok(ConverterGPU::Apple),
         0x10005 if is_docker().await => {
-            warn!("are you in a docker container? assuming NVIDIA, please open a PR and fix this if you're not.");
+            warn!(
+                "are you in a docker container? assuming NVIDIA, please open a PR and fix this if you're not."
+            );
             Ok(ConverterGPU::NVIDIA)
         }
         _ => Err(anyhow!("unknown GPU vendor: 0x{:X}", info.vendor)),
@@ -2,8 +2,10 @@ use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 use uuid::Uuid;
 
-const DEFAULT_BITRATE: u64 = 4 * 1_000_000;
-const BITRATE_MULTIPLIER: f64 = 2.5;
+use super::{codec::VideoCodec, loudness::Loudness, speed::ConversionSpeed};
+
+// tiny or very low framerate videos still need something to work with
+const MIN_BITRATE: u64 = 200_000;
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -13,9 +15,16 @@ pub struct Job {
     pub from: String,
     pub to: Option<String>,
     pub completed: bool,
+    /// extension of a subtitle file uploaded alongside the input, for burning in
+    pub subtitle_file: Option<String>,
     total_frames: Option<u64>,
-    bitrate: Option<u64>,
+    // the outer option is whether we've probed yet, some containers don't know their bitrate
+    source_bitrate: Option<Option<u64>>,
     fps: Option<u32>,
+    duration: Option<f64>,
+    has_video: Option<bool>,
+    resolution: Option<(u32, u32)>,
+    streams: Option<Vec<StreamInfo>>,
 }
 
 impl Job {
@@ -26,16 +35,48 @@ impl Job {
             from,
             to: None,
             completed: false,
+            subtitle_file: None,
             total_frames: None,
-            bitrate: None,
+            source_bitrate: None,
             fps: None,
+            duration: None,
+            has_video: None,
+            resolution: None,
+            streams: None,
         }
     }
 
-    // TODO: scale based on resolution
-    pub async fn bitrate(&mut self) -> anyhow::Result<u64> {
-        // Ok(DEFAULT_BITRATE)
-        if let Some(bitrate) = self.bitrate {
+    pub fn subtitle_path(&self) -> Option<String> {
+        self.subtitle_file
+            .as_ref()
+            .map(|ext| format!("input/{}.subtitles.{}", self.id, ext))
+    }
+
+    /// the video bitrate to encode this job with `codec` at, from a bits-per-pixel model of
+    /// the output's `resolution` and `fps`, scaled for `speed`. never more than the
+    /// source's own bitrate
+    pub async fn bitrate(
+        &mut self,
+        codec: VideoCodec,
+        speed: &ConversionSpeed,
+        (width, height): (u32, u32),
+        fps: f64,
+    ) -> anyhow::Result<u64> {
+        let pixels_per_second = width as f64 * height as f64 * fps.max(1.0);
+        // slower presets get a little more to work with, but never more than the source had
+        let bitrate = pixels_per_second * codec.bits_per_pixel() * speed.to_bitrate_mul();
+        let bitrate = (bitrate as u64).max(MIN_BITRATE);
+
+        Ok(match self.source_bitrate().await? {
+            Some(source) => bitrate.min(source),
+            None => bitrate,
+        })
+    }
+
+    /// the bitrate of the source's video stream, or of the whole file when the container
+    /// doesn't track it per stream (mkv, webm)
+    pub async fn source_bitrate(&mut self) -> anyhow::Result<Option<u64>> {
+        if let Some(bitrate) = self.source_bitrate {
             return Ok(bitrate);
         }
 
@@ -46,7 +87,7 @@ impl Job {
                 "-select_streams",
                 "v:0",
                 "-show_entries",
-                "stream=bit_rate",
+                "stream=bit_rate:format=bit_rate",
                 "-of",
                 "default=nokey=1:noprint_wrappers=1",
                 &format!("input/{}.{}", self.id, self.from),
@@ -54,14 +95,13 @@ impl Job {
             .output()
             .await?;
 
-        let bitrate = String::from_utf8(output.stdout)?;
-        let bitrate = match bitrate.trim().parse::<u64>() {
-            Ok(bitrate) => bitrate,
-            Err(_) => DEFAULT_BITRATE,
-        };
+        // the stream's comes first, and either can be "N/A"
+        let bitrate = String::from_utf8(output.stdout)?
+            .lines()
+            .find_map(|line| line.trim().parse::<u64>().ok());
 
-        self.bitrate = Some(bitrate);
-        Ok(((bitrate as f64) * BITRATE_MULTIPLIER) as u64)
+        self.source_bitrate = Some(bitrate);
+        Ok(bitrate)
     }
 
     pub async fn total_frames(&mut self) -> anyhow::Result<u64> {
@@ -144,10 +184,228 @@ impl Job {
         Ok(fps)
     }
 
-    pub async fn bitrate_and_fps(&mut self) -> anyhow::Result<(u64, u32)> {
-        let (bitrate, fps) = (self.bitrate().await?, self.fps().await?);
-        Ok((bitrate, fps))
+    pub async fn duration(&mut self) -> anyhow::Result<f64> {
+        if let Some(duration) = self.duration {
+            return Ok(duration);
+        }
+
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-show_entries",
+                "format=duration",
+                "-of",
+                "default=nokey=1:noprint_wrappers=1",
+                &format!("input/{}.{}", self.id, self.from),
+            ])
+            .output()
+            .await?;
+
+        let duration = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow::anyhow!("failed to parse duration: {}", e))?
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| anyhow::anyhow!("failed to parse duration: {}", e))?;
+
+        self.duration = Some(duration);
+        Ok(duration)
+    }
+
+    pub async fn has_video(&mut self) -> anyhow::Result<bool> {
+        if let Some(has_video) = self.has_video {
+            return Ok(has_video);
+        }
+
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v",
+                "-show_entries",
+                "stream=index:stream_disposition=attached_pic",
+                "-of",
+                "csv=p=0",
+                &format!("input/{}.{}", self.id, self.from),
+            ])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("failed to probe streams"));
+        }
+
+        // each line looks like "0,0" -- "index,attached_pic". cover art in
+        // audio files shows up as a video stream, so it doesn't count
+        let has_video = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow::anyhow!("failed to parse streams: {}", e))?
+            .lines()
+            .any(|line| line.trim().split(',').next_back() == Some("0"));
+
+        self.has_video = Some(has_video);
+        Ok(has_video)
     }
+
+    pub async fn resolution(&mut self) -> anyhow::Result<(u32, u32)> {
+        if let Some(resolution) = self.resolution {
+            return Ok(resolution);
+        }
+
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=width,height",
+                "-of",
+                "csv=s=x:p=0",
+                &format!("input/{}.{}", self.id, self.from),
+            ])
+            .output()
+            .await?;
+
+        // "1920x1080"
+        let resolution = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow::anyhow!("failed to parse resolution: {}", e))?;
+        let (width, height) = resolution
+            .trim()
+            .split_once('x')
+            .ok_or_else(|| anyhow::anyhow!("failed to parse resolution"))?;
+        let (width, height) = (width.parse::<u32>()?, height.parse::<u32>()?);
+
+        // phones store portrait video sideways along with a rotation, which ffmpeg applies
+        // on decode -- so report the size as it's displayed
+        let resolution = if self.rotation().await?.abs() % 180 == 90 {
+            (height, width)
+        } else {
+            (width, height)
+        };
+
+        self.resolution = Some(resolution);
+        Ok(resolution)
+    }
+
+    async fn rotation(&self) -> anyhow::Result<i32> {
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                // newer files use a display matrix, older ones a rotate tag
+                "-show_entries",
+                "stream_side_data=rotation:stream_tags=rotate",
+                "-of",
+                "default=nokey=1:noprint_wrappers=1",
+                &format!("input/{}.{}", self.id, self.from),
+            ])
+            .output()
+            .await?;
+
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .find_map(|line| line.trim().parse::<i32>().ok())
+            .unwrap_or(0))
+    }
+
+    pub async fn streams(&mut self) -> anyhow::Result<&[StreamInfo]> {
+        if self.streams.is_none() {
+            let output = Command::new("ffprobe")
+                .args([
+                    "-v",
+                    "error",
+                    "-show_streams",
+                    "-of",
+                    "json",
+                    &format!("input/{}.{}", self.id, self.from),
+                ])
+                .output()
+                .await?;
+
+            let probe: StreamsProbe = serde_json::from_slice(&output.stdout)
+                .map_err(|e| anyhow::anyhow!("failed to parse streams: {}", e))?;
+            self.streams = Some(probe.streams);
+        }
+
+        Ok(self.streams.as_deref().unwrap_or_default())
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamsProbe {
+    #[serde(default)]
+    streams: Vec<StreamInfo>,
+}
+
+// read straight from ffprobe's json output, so only serialization is camelCase
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct StreamInfo {
+    pub index: u32,
+    pub codec_type: String,
+    pub codec_name: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+    // ffprobe reports this as a string
+    pub sample_rate: Option<String>,
+    #[serde(default)]
+    pub disposition: StreamDisposition,
+    #[serde(default)]
+    pub tags: StreamTags,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct StreamTags {
+    pub language: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct StreamDisposition {
+    #[serde(default)]
+    pub attached_pic: u8,
+    #[serde(default)]
+    pub default: u8,
+}
+
+impl StreamInfo {
+    /// a real video stream, as opposed to cover art
+    pub fn is_video(&self) -> bool {
+        self.codec_type == "video" && self.disposition.attached_pic == 0
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.codec_type == "audio"
+    }
+
+    pub fn is_subtitle(&self) -> bool {
+        self.codec_type == "subtitle"
+    }
+
+    /// pq or hlg, or just the wide bt.2020 gamut, all of which look washed out when
+    /// shown as plain sdr
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.color_transfer.as_deref(),
+            Some("smpte2084" | "arib-std-b67")
+        ) || self.color_primaries.as_deref() == Some("bt2020")
+    }
+}
+
+/// what a finished job tells the client on top of the output itself
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobReport {
+    pub output_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_size_met: Option<bool>,
+    /// of each audio track in the input, if it was normalised
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub loudness: Vec<Loudness>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -157,6 +415,15 @@ pub enum ProgressUpdate {
     Frame(u64),
     #[serde(rename = "fps", rename_all = "camelCase")]
     FPS(f64),
+    #[serde(rename = "time", rename_all = "camelCase")]
+    Time(f64),
     #[serde(rename = "error", rename_all = "camelCase")]
     Error(String),
+    /// which pass of a two-pass encode the following updates belong to. both count
+    /// towards the same totals
+    #[serde(rename = "pass", rename_all = "camelCase")]
+    Pass(u8),
+    /// what the frame and time updates are counting towards, sent before any of them
+    #[serde(rename = "totals", rename_all = "camelCase")]
+    Totals { frames: Option<u64>, duration: f64 },
 }
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use super::{format::AudioSource, options::LoudnormOptions};
+
+/// what loudnorm's first pass measured of an audio track
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Loudness {
+    /// integrated loudness, in LUFS
+    pub integrated: f64,
+    /// in dBTP
+    pub true_peak: f64,
+    /// loudness range, in LU
+    pub range: f64,
+    pub threshold: f64,
+    // only the second pass needs this
+    #[serde(skip)]
+    pub offset: f64,
+}
+
+/// runs loudnorm's first pass over `source`, after `filters`. `input_args` and
+/// `output_args` should cut the input the same way the real conversion does
+pub async fn measure(
+    input: &str,
+    input_args: &[String],
+    output_args: &[String],
+    source: &AudioSource<'_>,
+    filters: &[String],
+    options: &LoudnormOptions,
+) -> anyhow::Result<Loudness> {
+    let mut filters = filters.to_vec();
+    filters.push(format!("{}:print_format=json", options.params()));
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats"])
+        .args(input_args)
+        .args(["-i", input])
+        .args(output_args)
+        .args(source.map_args(0, &filters))
+        .args(["-vn", "-sn", "-f", "null", "-"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to spawn ffmpeg: {}", e))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(anyhow!("failed to measure loudness: {}", stderr.trim()));
+    }
+
+    // the stats are the last thing loudnorm prints, as a flat object of strings
+    let stats = stderr
+        .rfind('{')
+        .zip(stderr.rfind('}'))
+        .and_then(|(start, end)| stderr.get(start..=end))
+        .ok_or_else(|| anyhow!("loudnorm didn't print its measurements"))?;
+    let stats: HashMap<String, String> = serde_json::from_str(stats)?;
+    let get = |key: &str| {
+        stats
+            .get(key)
+            .and_then(|value| value.parse::<f64>().ok())
+            .ok_or_else(|| anyhow!("loudnorm didn't measure {}", key))
+    };
+
+    Ok(Loudness {
+        integrated: get("input_i")?,
+        true_peak: get("input_tp")?,
+        range: get("input_lra")?,
+        threshold: get("input_thresh")?,
+        offset: get("target_offset")?,
+    })
+}
@@ -1,32 +1,53 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use anyhow::anyhow;
-use format::{Conversion, ConverterFormat};
-use job::{Job, ProgressUpdate};
+use codec::VideoCodec;
+use format::{Conversion, ConverterFormat, InputInfo};
+use job::{Job, JobReport, ProgressUpdate};
 use log::error;
 use log::info;
+use log::warn;
+use loudness::Loudness;
+use options::{ConversionOptions, RateControl, TARGET_SIZE_AUDIO_BITRATE, TrimMode};
 use speed::ConversionSpeed;
 use tokio::io::AsyncBufReadExt as _;
 use tokio::io::BufReader;
 use tokio::process::Command;
 use tokio::sync::mpsc;
 
+mod archive;
+pub mod codec;
+pub mod filter;
 pub mod format;
 pub mod gpu;
 pub mod job;
+pub mod loudness;
+pub mod options;
+pub mod preview;
 pub mod speed;
 
 pub struct Converter {
     pub conversion: Conversion,
     speed: ConversionSpeed,
+    options: ConversionOptions,
+    // measured during `convert`, for `report`
+    loudness: Mutex<Vec<Loudness>>,
 }
 
 impl Converter {
-    pub fn new(from: ConverterFormat, to: ConverterFormat, speed: ConversionSpeed) -> Self {
+    pub fn new(
+        from: ConverterFormat,
+        to: ConverterFormat,
+        speed: ConversionSpeed,
+        options: ConversionOptions,
+    ) -> Self {
         Self {
             conversion: Conversion::new(from, to),
             speed,
+            options,
+            loudness: Mutex::new(Vec::new()),
         }
     }
 
@@ -34,31 +55,189 @@ impl Converter {
         let (tx, rx) = mpsc::channel(1);
         let input_filename = format!("input/{}.{}", job.id, self.conversion.from);
         let output_filename = format!("output/{}.{}", job.id, self.conversion.to);
-        // let gpu = gpu::get_gpu().await;
-        // let bitrate = job.bitrate().await?;
-        // let fps = job.fps().await?;
-        // the above but we run in parallel
-        let (gpu, (bitrate, fps)) = tokio::try_join!(gpu::get_gpu(), job.bitrate_and_fps())?;
-        let args = self
+        // image sequences are written frame by frame into a directory, then zipped up
+        // into the usual output file once ffmpeg is done
+        let frames_dir = (self.conversion.to == ConverterFormat::ZIP)
+            .then(|| PathBuf::from(format!("output/{}", job.id)));
+        let has_video = job.has_video().await?;
+        if !has_video && !self.conversion.to.is_audio() {
+            return Err(anyhow!(
+                "input has no video stream to convert to {}",
+                self.conversion.to
+            ));
+        }
+
+        self.options.audio.validate(&self.conversion.to)?;
+
+        let trim = &self.options.trim;
+        if !trim.is_empty() {
+            trim.validate(job.duration().await?)?;
+        }
+        let input_args = trim.input_args();
+
+        if let Some(crop) = self.options.transform.crop {
+            let (width, height) = job.resolution().await?;
+            // these come straight from the client, so they could overflow
+            let right = crop.x.checked_add(crop.width);
+            let bottom = crop.y.checked_add(crop.height);
+            if right.is_none_or(|right| right > width)
+                || bottom.is_none_or(|bottom| bottom > height)
+            {
+                return Err(anyhow!(
+                    "crop doesn't fit inside the {}x{} input",
+                    width,
+                    height
+                ));
+            }
+        }
+
+        let remux_args = self
             .conversion
-            .to_args(&self.speed, &gpu, bitrate, fps)
-            .await?;
+            .remux_args(job.streams().await?, &self.options);
+        if remux_args.is_none() && trim.mode == TrimMode::Copy && !trim.is_empty() {
+            warn!(
+                "streams of {} can't be copied into {}, trimming precisely instead",
+                job.id, self.conversion.to
+            );
+        }
+        // two-pass encodes leave their stats files behind under this prefix
+        let mut passlog = None;
+        let mut first_pass = None;
+        // one chain per output audio track
+        let mut audio_filters = Vec::new();
+        let (args, gpu_args): (Vec<String>, &[&str]) = match remux_args {
+            Some(args) => {
+                info!(
+                    "streams of {} already fit {}, remuxing",
+                    job.id, self.conversion.to
+                );
+                // nothing gets decoded, so there's nothing to accelerate
+                (args, &[])
+            }
+            None => {
+                // the gpu decides which codec some formats end up in, which the bitrate
+                // depends on
+                let gpu = gpu::get_gpu().await?;
+                let (bitrate, fps) = if has_video {
+                    let codec = self.conversion.video_codec(&gpu, &self.options).await;
+                    self.bitrate_and_fps(job, codec).await?
+                } else {
+                    // audio-only inputs have nothing to probe for bitrate/fps
+                    (0, 0)
+                };
+
+                let mut options = self.options.clone();
+                let bitrate = match options.rate_control {
+                    RateControl::TargetSize { size } if self.conversion.to.is_video() => {
+                        let audio_bitrate = self.budget_audio(job, &mut options).await?;
+                        // only what's left after trimming has to fit
+                        let duration = trim.trimmed_duration(job.duration().await?);
+                        RateControl::target_bitrate(size, duration, audio_bitrate)?
+                    }
+                    _ => bitrate,
+                };
+
+                let burn_in = options.burn_in.filter(
+                    &input_filename,
+                    job.subtitle_path().as_deref(),
+                    job.streams().await?,
+                    trim.start.unwrap_or(0.0),
+                )?;
+                let hdr = job
+                    .streams()
+                    .await?
+                    .iter()
+                    .find(|s| s.is_video())
+                    .is_some_and(|s| s.is_hdr());
+                let input = InputInfo {
+                    bitrate,
+                    fps,
+                    hdr,
+                    burn_in,
+                };
+                let mut args = self
+                    .conversion
+                    .to_args(&self.speed, &gpu, &options, input)
+                    .await?;
+
+                // measuring loudness decodes every track, so it waits until to_args has
+                // checked the options
+                audio_filters = self
+                    .audio_filters(job, &input_filename, &input_args)
+                    .await?;
+
+                let gpu_args: &[&str] = match gpu {
+                    gpu::ConverterGPU::AMD => &["-hwaccel", "amf"],
+                    gpu::ConverterGPU::Intel => &["-hwaccel", "qsv"],
+                    gpu::ConverterGPU::NVIDIA => &["-hwaccel", "cuda"],
+                    gpu::ConverterGPU::Apple => &["-hwaccel", "videotoolbox"],
+                };
+
+                let prefix = format!("{}-passlog", job.id);
+                let passlog_path = format!("output/{}", prefix);
+                if let RateControl::TargetSize { .. } = options.rate_control
+                    && let Some(encoder) = video_encoder(&args)
+                    && let Some(pass_one) = RateControl::pass_args(encoder, 1, &passlog_path)
+                {
+                    // the same video stream the real pass encodes, minus the audio
+                    first_pass = Some(
+                        [
+                            [
+                                "-y",
+                                "-hide_banner",
+                                "-loglevel",
+                                "error",
+                                "-progress",
+                                "pipe:1",
+                            ]
+                            .map(String::from)
+                            .to_vec(),
+                            gpu_args.iter().map(|s| s.to_string()).collect(),
+                            input_args.clone(),
+                            vec!["-i".to_string(), input_filename.clone()],
+                            vec!["-map".to_string(), "0:V:0".to_string()],
+                            args.clone(),
+                            pass_one,
+                            trim.output_args(),
+                            ["-an", "-f", "null", "-"].map(String::from).to_vec(),
+                        ]
+                        .concat(),
+                    );
+                    args.extend(
+                        RateControl::pass_args(encoder, 2, &passlog_path).unwrap_or_default(),
+                    );
+                    passlog = Some(prefix);
+                }
+
+                (args, gpu_args)
+            }
+        };
+        let map_args =
+            self.conversion
+                .map_args(job.streams().await?, &self.options, &audio_filters);
+        let args = [map_args, args, trim.output_args()].concat();
         let args = args.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
         let args = args.as_slice();
 
-        let gpu_args: &[&str] = match gpu {
-            gpu::ConverterGPU::AMD => &["-hwaccel", "amf"],
-            gpu::ConverterGPU::Intel => &["-hwaccel", "qsv"],
-            gpu::ConverterGPU::NVIDIA => &["-hwaccel", "cuda"],
-            gpu::ConverterGPU::Apple => &["-hwaccel", "videotoolbox"],
+        let ffmpeg_output = match &frames_dir {
+            Some(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                format!(
+                    "{}/frame_%06d.{}",
+                    dir.display(),
+                    self.options.image_sequence.format.extension()
+                )
+            }
+            None => output_filename.clone(),
         };
 
         let command = &[
             &["-hide_banner", "-loglevel", "error", "-progress", "pipe:1"],
             gpu_args,
+            &input_args.iter().map(|s| s.as_str()).collect::<Vec<&str>>(),
             &["-i", &input_filename],
             args,
-            &[&output_filename],
+            &[&ffmpeg_output],
         ]
         .concat();
         let command = command
@@ -68,67 +247,349 @@ impl Converter {
 
         info!("running 'ffmpeg {}'", command.join(" "));
 
-        let mut process = Command::new("ffmpeg")
-            .args(command)
-            .stdin(std::process::Stdio::null())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .map_err(|e| anyhow!("failed to spawn ffmpeg: {}", e))?;
-
-        let stderr = process
-            .stderr
-            .take()
-            .ok_or_else(|| anyhow!("failed to take stderr"))?;
-
-        let tx_arc = Arc::new(tx);
-
-        let tx = Arc::clone(&tx_arc);
-
-        tokio::spawn(async move {
-            let mut lines = BufReader::new(stderr).lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                error!("{}", line);
-                tx.send(ProgressUpdate::Error(line)).await.unwrap();
-            }
-        });
-
-        let stdout = process
-            .stdout
-            .take()
-            .ok_or_else(|| anyhow!("failed to take stdout"))?;
-        let reader = BufReader::new(stdout);
-
-        let tx = Arc::clone(&tx_arc);
-
-        tokio::spawn(async move {
-            let mut lines = reader.lines();
-            while let Ok(Some(out)) = lines.next_line().await {
-                let mut map = HashMap::new();
-                for line in out.split("\n") {
-                    if let Some((k, v)) = line.split_once("=") {
-                        map.insert(k.trim(), v.trim());
+        // the client can still fall back on the totals from the upload without these
+        match self.totals(job).await {
+            Ok(totals) => tx.send(totals).await?,
+            Err(e) => warn!("failed to work out progress totals for {}: {}", job.id, e),
+        }
+
+        let tx = Arc::new(tx);
+        let finish = Finish {
+            passlog,
+            frames_dir,
+            output_filename,
+        };
+        match first_pass {
+            None => {
+                let mut process = spawn_ffmpeg(&command, &tx)?;
+                // holding on to a sender until ffmpeg exits keeps the job open while we
+                // finish up the output
+                tokio::spawn(async move {
+                    let succeeded = matches!(process.wait().await, Ok(status) if status.success());
+                    finish.run(succeeded, &tx).await;
+                });
+            }
+            // the first pass only writes stats, but it takes about as long as the second,
+            // so its progress is reported too
+            Some(first_pass) => {
+                let id = job.id;
+                tokio::spawn(async move {
+                    info!("running first pass for {}", id);
+                    let _ = tx.send(ProgressUpdate::Pass(1)).await;
+                    let passed = run_ffmpeg(&first_pass, &tx).await;
+                    if !passed {
+                        let _ = tx
+                            .send(ProgressUpdate::Error("first pass failed".to_string()))
+                            .await;
+                        finish.run(false, &tx).await;
+                        return;
                     }
-                }
 
-                let mut reports = Vec::new();
+                    let _ = tx.send(ProgressUpdate::Pass(2)).await;
+                    let succeeded = run_ffmpeg(&command, &tx).await;
+                    finish.run(succeeded, &tx).await;
+                });
+            }
+        }
+
+        Ok(rx)
+    }
+
+    // the bitrate is modelled on what actually gets encoded, after cropping, resizing
+    // and any frame rate change. the fps is the source's
+    async fn bitrate_and_fps(
+        &self,
+        job: &mut Job,
+        codec: VideoCodec,
+    ) -> anyhow::Result<(u64, u32)> {
+        let fps = job.fps().await?;
+        let resolution = match self.options.transform.crop {
+            Some(crop) => (crop.width, crop.height),
+            None => job.resolution().await?,
+        };
+        let resolution = self.options.resize.output_size(resolution);
+        let output_fps = self.options.frame_rate.output_fps(fps.max(1) as f64);
+        let bitrate = job
+            .bitrate(codec, &self.speed, resolution, output_fps)
+            .await?;
+        Ok((bitrate, fps))
+    }
+
+    // target sizes need to know the audio bitrate up front, so pin it down rather than
+    // leaving it to the encoder's default. returns what the audio will take up
+    async fn budget_audio(
+        &self,
+        job: &mut Job,
+        options: &mut ConversionOptions,
+    ) -> anyhow::Result<u64> {
+        // every kept track is encoded at the same bitrate
+        let tracks = self
+            .conversion
+            .audio_sources(job.streams().await?, options)
+            .len() as u64;
+        if tracks == 0 {
+            return Ok(0);
+        }
+
+        if options.audio_codec.is_some_and(|c| c.is_lossless()) {
+            return Err(anyhow!("lossless audio can't be fit into a target size"));
+        }
+
+        let bitrate = *options
+            .audio
+            .bitrate
+            .get_or_insert(TARGET_SIZE_AUDIO_BITRATE);
+        Ok(bitrate.saturating_mul(tracks))
+    }
+
+    // progress is counted against the output, which trimming makes shorter than the input
+    // and a new frame rate changes the frame count of
+    async fn totals(&self, job: &mut Job) -> anyhow::Result<ProgressUpdate> {
+        let source_duration = job.duration().await?;
+        let duration = if self.options.trim.is_empty() {
+            source_duration
+        } else {
+            self.options.trim.trimmed_duration(source_duration)
+        };
+
+        let frames =
+            if job.has_video().await? && !self.conversion.to.is_audio() && source_duration > 0.0 {
+                let frames = job.total_frames().await? as f64 * duration / source_duration;
+                let source_fps = job.fps().await?.max(1);
+                Some((frames * self.frame_ratio(source_fps)?).round() as u64)
+            } else {
+                None
+            };
+
+        Ok(ProgressUpdate::Totals { frames, duration })
+    }
+
+    // how many frames the output gets per source frame, after whatever fps filter the
+    // target format puts in
+    fn frame_ratio(&self, source_fps: u32) -> anyhow::Result<f64> {
+        let options = &self.options;
+        let output_fps = match self.conversion.to {
+            ConverterFormat::GIF => options.gif.output_fps(source_fps)? as f64,
+            ConverterFormat::WebP | ConverterFormat::APNG => {
+                options.animation.output_fps(source_fps, 30)? as f64
+            }
+            ConverterFormat::ZIP => {
+                return Ok(options.image_sequence.frame_ratio(source_fps as f64));
+            }
+            to if to.is_video() => options.frame_rate.output_fps(source_fps as f64),
+            _ => source_fps as f64,
+        };
+        Ok(output_fps / source_fps as f64)
+    }
+
+    // the filters for each output audio track. loudnorm needs each one measured first
+    async fn audio_filters(
+        &self,
+        job: &mut Job,
+        input: &str,
+        input_args: &[String],
+    ) -> anyhow::Result<Vec<Vec<String>>> {
+        let loudnorm = self.options.loudnorm.as_ref();
+        let base = self.options.audio.filters(loudnorm.is_some());
+        let id = job.id;
+        let streams = job.streams().await?;
+        let sources = self.conversion.audio_sources(streams, &self.options);
 
-                if let Some(frame) = map.get("frame").and_then(|s| s.parse().ok()) {
-                    reports.push(ProgressUpdate::Frame(frame));
+        let Some(loudnorm) = loudnorm else {
+            return Ok(vec![base; sources.len()]);
+        };
+        loudnorm.validate()?;
+
+        let output_args = self.options.trim.output_args();
+        let mut filters = Vec::new();
+        let mut measurements = Vec::new();
+        for source in &sources {
+            let measured =
+                loudness::measure(input, input_args, &output_args, source, &base, loudnorm).await?;
+            info!("audio of {} measured at {} LUFS", id, measured.integrated);
+
+            let mut chain = base.clone();
+            // silence measures as -inf, and there's nothing to bring up anyway
+            if measured.integrated.is_finite() {
+                let sample_rate = self
+                    .options
+                    .audio
+                    .sample_rate
+                    .or(source.sample_rate())
+                    .unwrap_or(48000);
+                chain.extend(loudnorm.filters(&measured, sample_rate));
+            } else {
+                warn!("audio of {} is silent, not normalising it", id);
+            }
+            filters.push(chain);
+            measurements.push(measured);
+        }
+
+        *self.loudness.lock().unwrap() = measurements;
+        Ok(filters)
+    }
+
+    /// sums up a finished job, given the size of what it wrote
+    pub fn report(&self, output_size: u64) -> JobReport {
+        let target_size_met = match self.options.rate_control {
+            RateControl::TargetSize { size } => {
+                let met = output_size <= size;
+                if !met {
+                    warn!("missed target size of {} bytes with {}", size, output_size);
                 }
+                Some(met)
+            }
+            _ => None,
+        };
+
+        JobReport {
+            output_size,
+            target_size_met,
+            loudness: self.loudness.lock().unwrap().clone(),
+        }
+    }
+}
 
-                if let Some(fps) = map.get("fps").and_then(|s| s.parse().ok()) {
-                    reports.push(ProgressUpdate::FPS(fps));
+// starts ffmpeg with `args`, forwarding its progress and errors to `tx`
+fn spawn_ffmpeg(
+    args: &[String],
+    tx: &Arc<mpsc::Sender<ProgressUpdate>>,
+) -> anyhow::Result<tokio::process::Child> {
+    let mut process = Command::new("ffmpeg")
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to spawn ffmpeg: {}", e))?;
+
+    let stderr = process
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("failed to take stderr"))?;
+
+    let tx_err = Arc::clone(tx);
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            error!("{}", line);
+            tx_err.send(ProgressUpdate::Error(line)).await.unwrap();
+        }
+    });
+
+    let stdout = process
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("failed to take stdout"))?;
+    let reader = BufReader::new(stdout);
+
+    let tx = Arc::clone(tx);
+
+    tokio::spawn(async move {
+        let mut lines = reader.lines();
+        while let Ok(Some(out)) = lines.next_line().await {
+            let mut map = HashMap::new();
+            for line in out.split("\n") {
+                if let Some((k, v)) = line.split_once("=") {
+                    map.insert(k.trim(), v.trim());
                 }
+            }
 
-                for report in reports {
-                    if tx.send(report).await.is_err() {
-                        break;
-                    }
+            let mut reports = Vec::new();
+
+            if let Some(frame) = map.get("frame").and_then(|s| s.parse().ok()) {
+                reports.push(ProgressUpdate::Frame(frame));
+            }
+
+            if let Some(fps) = map.get("fps").and_then(|s| s.parse().ok()) {
+                reports.push(ProgressUpdate::FPS(fps));
+            }
+
+            // how much of the output has been written so far, in microseconds
+            if let Some(time) = map.get("out_time_us").and_then(|s| s.parse::<u64>().ok()) {
+                reports.push(ProgressUpdate::Time(time as f64 / 1_000_000.0));
+            }
+
+            for report in reports {
+                if tx.send(report).await.is_err() {
+                    break;
                 }
             }
-        });
+        }
+    });
 
-        Ok(rx)
+    Ok(process)
+}
+
+// runs ffmpeg to the end, returning whether it succeeded
+async fn run_ffmpeg(args: &[String], tx: &Arc<mpsc::Sender<ProgressUpdate>>) -> bool {
+    match spawn_ffmpeg(args, tx) {
+        Ok(mut process) => matches!(process.wait().await, Ok(status) if status.success()),
+        Err(e) => {
+            error!("{}", e);
+            false
+        }
+    }
+}
+
+// what's left to do once ffmpeg has exited
+struct Finish {
+    passlog: Option<String>,
+    frames_dir: Option<PathBuf>,
+    output_filename: String,
+}
+
+impl Finish {
+    async fn run(self, succeeded: bool, tx: &mpsc::Sender<ProgressUpdate>) {
+        if let Some(passlog) = self.passlog {
+            remove_with_prefix("output", &passlog).await;
+        }
+
+        let Some(dir) = self.frames_dir else {
+            return;
+        };
+
+        if succeeded {
+            let out = PathBuf::from(self.output_filename);
+            let zip_dir = dir.clone();
+            let result = tokio::task::spawn_blocking(move || archive::zip_dir(&zip_dir, &out))
+                .await
+                .map_err(|e| anyhow!("{}", e))
+                .and_then(|r| r);
+            if let Err(e) = result {
+                error!("failed to zip image sequence: {}", e);
+                let _ = tx
+                    .send(ProgressUpdate::Error(format!(
+                        "failed to zip image sequence: {}",
+                        e
+                    )))
+                    .await;
+            }
+        }
+
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            error!("failed to remove frames directory: {}", e);
+        }
+    }
+}
+
+// the encoder picked by `-c:v` in a list of ffmpeg args
+fn video_encoder(args: &[String]) -> Option<&str> {
+    args.windows(2)
+        .find(|pair| pair[0] == "-c:v")
+        .map(|pair| pair[1].as_str())
+}
+
+async fn remove_with_prefix(dir: &str, prefix: &str) {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with(prefix)
+            && let Err(e) = tokio::fs::remove_file(entry.path()).await
+        {
+            error!("failed to remove {}: {}", entry.path().display(), e);
+        }
     }
 }
@@ -0,0 +1,1489 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    codec::{self, AudioCodec, VideoCodec},
+    filter::{FilterChain, Scale, Scaler},
+    format::ConverterFormat,
+    job::StreamInfo,
+    loudness::Loudness,
+};
+
+// what the audio gets when squeezing into a target size and no audio bitrate was asked for
+pub const TARGET_SIZE_AUDIO_BITRATE: u64 = 128_000;
+// headroom for container overhead and encoders overshooting a little
+const TARGET_SIZE_MARGIN: f64 = 0.96;
+// below this there's no point even trying
+const MIN_TARGET_VIDEO_BITRATE: u64 = 50_000;
+
+// sample rates libopus can encode at -- anything else makes ffmpeg bail out
+const OPUS_SAMPLE_RATES: [u32; 5] = [48000, 24000, 16000, 12000, 8000];
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConversionOptions {
+    /// always re-encode, even when the streams could be copied into the new container as-is
+    pub reencode: bool,
+    pub video_codec: Option<VideoCodec>,
+    pub audio_codec: Option<AudioCodec>,
+    pub rate_control: RateControl,
+    pub audio: AudioOptions,
+    pub animation: AnimationOptions,
+    pub image_sequence: ImageSequenceOptions,
+    pub resize: ResizeOptions,
+    pub trim: TrimOptions,
+    pub transform: TransformOptions,
+    pub frame_rate: FrameRateOptions,
+    pub gif: GifOptions,
+    pub subtitles: SubtitleSelection,
+    pub burn_in: BurnInOptions,
+    pub audio_tracks: AudioTracks,
+    pub metadata: MetadataOptions,
+    pub tonemap: TonemapOptions,
+    /// normalise loudness to EBU R128, with the given targets
+    pub loudnorm: Option<LoudnormOptions>,
+}
+
+impl ConversionOptions {
+    /// the (camelCase) names of the options that do something for a given output format
+    pub fn applicable_to(format: &ConverterFormat) -> Vec<&'static str> {
+        let mut options = Vec::new();
+        if format.is_video() || format.is_audio() {
+            options.push("reencode");
+        }
+        if format.is_video() {
+            options.extend(["videoCodec", "rateControl", "frameRate"]);
+        }
+        if format.has_audio() {
+            options.extend(["audioCodec", "audio", "audioTracks", "loudnorm"]);
+        }
+        if matches!(format, ConverterFormat::WebP | ConverterFormat::APNG) {
+            options.push("animation");
+        }
+        if *format == ConverterFormat::GIF {
+            options.push("gif");
+        }
+        if matches!(
+            format,
+            ConverterFormat::MP4
+                | ConverterFormat::MOV
+                | ConverterFormat::MKV
+                | ConverterFormat::WebM
+        ) {
+            options.push("subtitles");
+        }
+        if *format == ConverterFormat::ZIP {
+            options.push("imageSequence");
+        }
+        if !format.is_audio() {
+            options.extend(["resize", "transform", "burnIn", "tonemap"]);
+        }
+        options.extend(["trim", "metadata"]);
+        options
+    }
+
+    /// whether any of the options only make sense when re-encoding. codec choices are
+    /// checked against the source streams separately
+    pub fn requires_reencode(&self) -> bool {
+        self.reencode
+            || self.rate_control != RateControl::Bitrate
+            || self.audio.bitrate.is_some()
+            || self.audio.sample_rate.is_some()
+            || self.audio.channels.is_some()
+            || self.audio.gain.is_some_and(|gain| gain != 0.0)
+            || self.audio.downmix.is_some()
+            || !self.resize.is_empty()
+            || (!self.trim.is_empty() && self.trim.mode == TrimMode::Precise)
+            || !self.transform.is_empty()
+            || self.frame_rate.fps.is_some()
+            || self.burn_in.source.is_some()
+            || self.audio_tracks == AudioTracks::Mix
+            || self.loudnorm.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum RateControl {
+    /// aim for a bitrate based on the source's
+    #[default]
+    Bitrate,
+    /// constant quality from 0 (worst) to 100 (best), mapped onto each encoder's own scale
+    Quality { quality: u8 },
+    /// fit the whole output into `size` bytes
+    TargetSize { size: u64 },
+}
+
+// maps a 0-100 quality onto an encoder scale where lower is better, clamped to the part
+// of that scale that's actually useful rather than its full range
+fn quality_scale(quality: u8, best: u32, worst: u32) -> String {
+    let value = worst as f64 - (worst - best) as f64 * quality.min(100) as f64 / 100.0;
+    (value.round() as u32).to_string()
+}
+
+impl RateControl {
+    pub fn validate(&self, to: &ConverterFormat) -> anyhow::Result<()> {
+        match self {
+            RateControl::Quality { quality } if *quality > 100 => {
+                Err(anyhow!("quality must be between 0 and 100"))
+            }
+            RateControl::TargetSize { size: 0 } => {
+                Err(anyhow!("target size must be greater than 0"))
+            }
+            // only the video bitrate gets budgeted, nothing else would end up the right size
+            RateControl::TargetSize { .. } if !to.is_video() => {
+                Err(anyhow!("can't target a size when converting to {}", to))
+            }
+            // gifs, animations and audio have their own quality settings
+            RateControl::Quality { .. } if !to.is_video() => Err(anyhow!(
+                "can't set a video quality when converting to {}",
+                to
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// the video bitrate that fits `size` bytes of output over `duration` seconds,
+    /// after setting aside `audio_bitrate` for the soundtrack
+    pub fn target_bitrate(size: u64, duration: f64, audio_bitrate: u64) -> anyhow::Result<u64> {
+        if duration <= 0.0 {
+            return Err(anyhow!("can't target a size without knowing the duration"));
+        }
+
+        let total = (size as f64 * 8.0 / duration * TARGET_SIZE_MARGIN) as u64;
+        let video = total.saturating_sub(audio_bitrate);
+        if video < MIN_TARGET_VIDEO_BITRATE {
+            return Err(anyhow!(
+                "{} bytes is too small for {:.1} seconds of video",
+                size,
+                duration
+            ));
+        }
+
+        Ok(video)
+    }
+
+    /// args for one pass of a two-pass encode, or None if `encoder` can't do two passes.
+    /// `passlog` is the prefix for the stats files shared between the passes
+    pub fn pass_args(encoder: &str, pass: u8, passlog: &str) -> Option<Vec<String>> {
+        match encoder {
+            // libx265 ignores -pass, it wants its own params instead
+            "libx265" => Some(vec![
+                "-x265-params".to_string(),
+                format!("pass={}:stats={}.log", pass, passlog),
+            ]),
+            "libx264" | "libvpx" | "libvpx-vp9" | "libaom-av1" | "mpeg4" | "wmv2" => Some(vec![
+                "-pass".to_string(),
+                pass.to_string(),
+                "-passlogfile".to_string(),
+                passlog.to_string(),
+            ]),
+            _ => None,
+        }
+    }
+
+    /// video rate control args for `encoder`, with `bitrate` in bits per second
+    pub fn to_args(&self, encoder: &str, bitrate: u64) -> Vec<String> {
+        let quality = match self {
+            RateControl::Bitrate => {
+                return vec!["-b:v".to_string(), bitrate.to_string()];
+            }
+            // the cap keeps single-pass encoders from blowing the budget on one scene
+            RateControl::TargetSize { .. } => {
+                return vec![
+                    "-b:v".to_string(),
+                    bitrate.to_string(),
+                    "-maxrate".to_string(),
+                    (bitrate * 3 / 2).to_string(),
+                    "-bufsize".to_string(),
+                    (bitrate * 2).to_string(),
+                ];
+            }
+            RateControl::Quality { quality } => *quality,
+        };
+
+        match encoder {
+            "libx264" => vec!["-crf".to_string(), quality_scale(quality, 16, 40)],
+            // x265 crf 28 looks about like x264 crf 23
+            "libx265" => vec!["-crf".to_string(), quality_scale(quality, 18, 42)],
+            // vp9 and libaom only do constant quality with the bitrate zeroed out
+            "libvpx-vp9" => vec![
+                "-crf".to_string(),
+                quality_scale(quality, 15, 50),
+                "-b:v".to_string(),
+                "0".to_string(),
+            ],
+            // vp8 has no pure crf mode, the bitrate becomes a cap instead
+            "libvpx" => vec![
+                "-crf".to_string(),
+                quality_scale(quality, 10, 50),
+                "-b:v".to_string(),
+                bitrate.to_string(),
+            ],
+            "libsvtav1" => vec!["-crf".to_string(), quality_scale(quality, 20, 55)],
+            "libaom-av1" => vec![
+                "-crf".to_string(),
+                quality_scale(quality, 20, 55),
+                "-b:v".to_string(),
+                "0".to_string(),
+            ],
+            e if e.ends_with("_nvenc") => vec![
+                "-rc".to_string(),
+                "vbr".to_string(),
+                "-cq".to_string(),
+                quality_scale(quality, 16, 40),
+                "-b:v".to_string(),
+                "0".to_string(),
+            ],
+            // setting global_quality without a bitrate puts qsv in ICQ mode
+            e if e.ends_with("_qsv") => {
+                vec![
+                    "-global_quality".to_string(),
+                    quality_scale(quality, 16, 40),
+                ]
+            }
+            e if e.ends_with("_amf") => {
+                let qp = quality_scale(quality, 16, 40);
+                vec![
+                    "-rc".to_string(),
+                    "cqp".to_string(),
+                    "-qp_i".to_string(),
+                    qp.clone(),
+                    "-qp_p".to_string(),
+                    qp.clone(),
+                    "-qp_b".to_string(),
+                    qp,
+                ]
+            }
+            e if e.ends_with("_vaapi") => vec![
+                "-rc_mode".to_string(),
+                "CQP".to_string(),
+                "-qp".to_string(),
+                quality_scale(quality, 16, 40),
+            ],
+            // videotoolbox already takes 0-100, higher is better
+            e if e.ends_with("_videotoolbox") => {
+                vec!["-q:v".to_string(), quality.min(100).to_string()]
+            }
+            "mpeg4" | "wmv2" => vec!["-q:v".to_string(), quality_scale(quality, 2, 31)],
+            _ => {
+                warn!(
+                    "{} has no constant quality mode, using bitrate instead",
+                    encoder
+                );
+                vec!["-b:v".to_string(), bitrate.to_string()]
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AudioOptions {
+    /// target bitrate in bits per second, ignored for lossless formats
+    pub bitrate: Option<u64>,
+    /// output sample rate in Hz
+    pub sample_rate: Option<u32>,
+    /// output channel count
+    pub channels: Option<u8>,
+    /// drop the audio altogether
+    pub strip: bool,
+    /// volume change in dB, negative to make it quieter
+    pub gain: Option<f64>,
+    pub downmix: Option<Downmix>,
+}
+
+impl AudioOptions {
+    /// checks the options make sense together, and for `to`
+    pub fn validate(&self, to: &ConverterFormat) -> anyhow::Result<()> {
+        if self.strip && to.is_audio() {
+            return Err(anyhow!("can't strip the audio from an audio-only output"));
+        }
+        if let Some(gain) = self.gain
+            && !(-60.0..=60.0).contains(&gain)
+        {
+            return Err(anyhow!("gain must be between -60 and 60 dB"));
+        }
+        if self.downmix.is_some() && self.channels.is_some() {
+            return Err(anyhow!("downmix and channels can't be used together"));
+        }
+        Ok(())
+    }
+
+    /// filters run over every audio track. `normalised` tracks get their level from
+    /// loudnorm instead, which would undo any gain
+    pub fn filters(&self, normalised: bool) -> Vec<String> {
+        let mut filters = Vec::new();
+        if let Some(downmix) = self.downmix {
+            filters.push(downmix.filter().to_string());
+        }
+        match self.gain {
+            Some(_) if normalised => warn!("loudness is being normalised, ignoring gain"),
+            Some(gain) if gain != 0.0 => filters.push(format!("volume={}dB", gain)),
+            _ => {}
+        }
+        filters
+    }
+
+    pub fn to_args(&self, to: &ConverterFormat, codec: &AudioCodec) -> anyhow::Result<Vec<String>> {
+        let mut args = Vec::new();
+
+        match self.bitrate {
+            Some(_) if codec.is_lossless() => {
+                warn!("{} is lossless, ignoring audio bitrate", codec);
+            }
+            Some(0) => return Err(anyhow!("audio bitrate must be greater than 0")),
+            Some(bitrate) => {
+                args.push("-b:a".to_string());
+                args.push(bitrate.to_string());
+            }
+            // audio-only outputs get a decent default rather than ffmpeg's, which is
+            // tuned for video soundtracks
+            None if to.is_audio() => match codec {
+                AudioCodec::MP3 => args.extend(["-q:a".to_string(), "2".to_string()]),
+                AudioCodec::AAC => args.extend(["-b:a".to_string(), "192k".to_string()]),
+                AudioCodec::Vorbis => args.extend(["-q:a".to_string(), "5".to_string()]),
+                AudioCodec::Opus => args.extend(["-b:a".to_string(), "128k".to_string()]),
+                _ => {}
+            },
+            None => {}
+        }
+
+        if let Some(sample_rate) = self.sample_rate {
+            if sample_rate == 0 {
+                return Err(anyhow!("sample rate must be greater than 0"));
+            }
+            if *codec == AudioCodec::Opus && !OPUS_SAMPLE_RATES.contains(&sample_rate) {
+                return Err(anyhow!(
+                    "opus doesn't support a sample rate of {} Hz (allowed: 48000, 24000, 16000, 12000, 8000)",
+                    sample_rate
+                ));
+            }
+            args.push("-ar".to_string());
+            args.push(sample_rate.to_string());
+        }
+
+        if let Some(channels) = self.channels {
+            if channels == 0 {
+                return Err(anyhow!("channel count must be greater than 0"));
+            }
+            args.push("-ac".to_string());
+            args.push(channels.to_string());
+        }
+
+        Ok(args)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Downmix {
+    /// surround (5.1, 7.1, ...) down to two channels
+    Stereo,
+    Mono,
+}
+
+impl Downmix {
+    // letting aformat pick the layout has swresample do the downmix, with the standard
+    // matrix for whatever layout the source has -- pan would need one per layout
+    fn filter(&self) -> &'static str {
+        match self {
+            Downmix::Stereo => "aformat=channel_layouts=stereo",
+            Downmix::Mono => "aformat=channel_layouts=mono",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AnimationOptions {
+    /// maximum output frame rate, never higher than the source
+    pub fps: Option<u32>,
+    /// how many times the animation plays, 0 (the default) loops forever
+    pub loop_count: Option<u32>,
+    /// 0-100, where 100 is lossless
+    pub quality: Option<u8>,
+}
+
+impl AnimationOptions {
+    pub fn output_fps(&self, source_fps: u32, default_cap: u32) -> anyhow::Result<u32> {
+        match self.fps {
+            Some(0) => Err(anyhow!("animation fps must be greater than 0")),
+            Some(fps) => Ok(fps.min(source_fps)),
+            None => Ok(default_cap.min(source_fps)),
+        }
+    }
+
+    pub fn quality(&self) -> anyhow::Result<Option<u8>> {
+        match self.quality {
+            Some(quality) if quality > 100 => {
+                Err(anyhow!("animation quality must be between 0 and 100"))
+            }
+            quality => Ok(quality),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GifPreset {
+    /// for reaction clips and chat, where size matters most
+    Small,
+    #[default]
+    Balanced,
+    /// for screen recordings and anything with fine detail or gradients
+    Quality,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GifDither {
+    None,
+    Bayer,
+    FloydSteinberg,
+    Sierra2,
+    SierraLite,
+}
+
+impl GifDither {
+    fn filter_value(&self) -> &'static str {
+        match self {
+            GifDither::None => "none",
+            GifDither::Bayer => "bayer",
+            GifDither::FloydSteinberg => "floyd_steinberg",
+            GifDither::Sierra2 => "sierra2",
+            GifDither::SierraLite => "sierra2_4a",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GifPalette {
+    /// one palette for the whole gif
+    Global,
+    /// one palette for the whole gif, built from the parts that move. good for screen
+    /// recordings with a static background
+    Diff,
+    /// a new palette for every frame. best colours, biggest files
+    PerFrame,
+}
+
+/// anything left unset comes from the preset
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GifOptions {
+    pub preset: GifPreset,
+    /// maximum width, unless `resize` says otherwise
+    pub width: Option<u32>,
+    /// maximum frame rate, never higher than the source
+    pub fps: Option<u32>,
+    /// palette size, 2-256
+    pub colors: Option<u16>,
+    pub dither: Option<GifDither>,
+    pub palette: Option<GifPalette>,
+    /// how many times the gif plays, 0 (the default) loops forever
+    pub loop_count: Option<u32>,
+    /// pixels more transparent than this (0-255) are fully transparent, the rest opaque
+    pub alpha_threshold: Option<u8>,
+}
+
+impl GifOptions {
+    // width, fps, colors, dither, palette
+    fn preset_defaults(&self) -> (u32, u32, u16, GifDither, GifPalette) {
+        match self.preset {
+            GifPreset::Small => (480, 12, 64, GifDither::Bayer, GifPalette::Global),
+            // what gifs always used to get
+            GifPreset::Balanced => (800, 24, 64, GifDither::Bayer, GifPalette::Global),
+            GifPreset::Quality => (1280, 30, 256, GifDither::SierraLite, GifPalette::Diff),
+        }
+    }
+
+    pub fn width(&self) -> anyhow::Result<u32> {
+        match self.width {
+            Some(0) => Err(anyhow!("gif width must be greater than 0")),
+            width => Ok(width.unwrap_or(self.preset_defaults().0)),
+        }
+    }
+
+    pub fn output_fps(&self, source_fps: u32) -> anyhow::Result<u32> {
+        let fps = match self.fps {
+            Some(0) => return Err(anyhow!("gif fps must be greater than 0")),
+            fps => fps.unwrap_or(self.preset_defaults().1),
+        };
+        Ok(fps.min(source_fps.max(1)))
+    }
+
+    pub fn fps_filter(&self, source_fps: u32) -> anyhow::Result<String> {
+        Ok(format!("fps={}", self.output_fps(source_fps)?))
+    }
+
+    /// the palettegen/paletteuse graph, which has to come last in the filter chain
+    pub fn palette_filter(&self) -> anyhow::Result<String> {
+        let (_, _, colors, dither, palette) = self.preset_defaults();
+        let colors = self.colors.unwrap_or(colors);
+        if !(2..=256).contains(&colors) {
+            return Err(anyhow!("gif colors must be between 2 and 256"));
+        }
+        let dither = self.dither.unwrap_or(dither);
+        let palette = self.palette.unwrap_or(palette);
+
+        let stats_mode = match palette {
+            GifPalette::Global => "full",
+            GifPalette::Diff => "diff",
+            GifPalette::PerFrame => "single",
+        };
+        let mut paletteuse = format!("paletteuse=dither={}", dither.filter_value());
+        match palette {
+            // per-frame palettes have to be picked up as they come
+            GifPalette::PerFrame => paletteuse.push_str(":new=1"),
+            // only redraw the part of each frame that changed
+            GifPalette::Diff => paletteuse.push_str(":diff_mode=rectangle"),
+            GifPalette::Global => {}
+        }
+        if let Some(threshold) = self.alpha_threshold {
+            paletteuse.push_str(&format!(":alpha_threshold={}", threshold));
+        }
+
+        Ok(format!(
+            "split[s0][s1];[s0]palettegen=max_colors={}:stats_mode={}[p];[s1][p]{}",
+            colors, stats_mode, paletteuse
+        ))
+    }
+
+    pub fn output_args(&self) -> Vec<String> {
+        // the gif muxer counts repeats after the first play, with -1 for none at all
+        let repeats = match self.loop_count.unwrap_or(0) {
+            0 => 0,
+            1 => -1,
+            plays => plays as i64 - 1,
+        };
+        vec!["-loop".to_string(), repeats.to_string()]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    pub fn encoder_args(&self) -> Vec<String> {
+        match self {
+            ImageFormat::Png => vec!["-c:v".to_string(), "png".to_string()],
+            ImageFormat::Jpeg => vec![
+                "-c:v".to_string(),
+                "mjpeg".to_string(),
+                "-q:v".to_string(),
+                "2".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ImageSequenceOptions {
+    pub format: ImageFormat,
+    /// keep every nth frame
+    pub step: Option<u32>,
+    /// sample frames at a fixed rate instead of by step
+    pub fps: Option<f64>,
+}
+
+impl ImageSequenceOptions {
+    /// pushes the frame sampling onto `filters` and returns the rest of the args
+    pub fn to_args(&self, filters: &mut FilterChain) -> anyhow::Result<Vec<String>> {
+        let mut args = Vec::new();
+
+        match (self.step, self.fps) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "image sequences take either a step or an fps, not both"
+                ));
+            }
+            (Some(0), None) => return Err(anyhow!("frame step must be greater than 0")),
+            (Some(step), None) => {
+                filters.push(format!("select=not(mod(n\\,{}))", step));
+                // select leaves gaps in the timestamps, don't let ffmpeg fill them back in
+                args.push("-fps_mode".to_string());
+                args.push("vfr".to_string());
+            }
+            (None, Some(fps)) if fps <= 0.0 => {
+                return Err(anyhow!("image sequence fps must be greater than 0"));
+            }
+            (None, Some(fps)) => filters.push(format!("fps={}", fps)),
+            (None, None) => {}
+        }
+
+        args.extend(self.format.encoder_args());
+        args.push("-an".to_string());
+        Ok(args)
+    }
+
+    /// how many frames come out per source frame, at `source_fps`
+    pub fn frame_ratio(&self, source_fps: f64) -> f64 {
+        match (self.step, self.fps) {
+            (Some(step), _) if step > 0 => 1.0 / step as f64,
+            (_, Some(fps)) if fps > 0.0 => fps / source_fps,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResizeFit {
+    /// fit inside the exact size and pad out the rest
+    #[default]
+    Fit,
+    /// cover the exact size and crop off whatever hangs over
+    Fill,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResizeOptions {
+    /// shrink to fit within these, keeping the aspect ratio. smaller sources are left alone
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// exact output size. with only one of them set the other follows the aspect ratio
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// what to do when an exact size doesn't match the source's aspect ratio
+    pub fit: ResizeFit,
+    /// scale to a percentage of the source size
+    pub percent: Option<f64>,
+}
+
+impl ResizeOptions {
+    pub fn is_empty(&self) -> bool {
+        self.max_width.is_none()
+            && self.max_height.is_none()
+            && self.width.is_none()
+            && self.height.is_none()
+            && self.percent.is_none()
+    }
+
+    /// roughly the size a `width`x`height` input comes out at, for estimating bitrates
+    pub fn output_size(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        let (w, h) = (width.max(1) as f64, height.max(1) as f64);
+        let factor = if let Some(percent) = self.percent {
+            percent / 100.0
+        } else if self.max_width.is_some() || self.max_height.is_some() {
+            let fit_width = self.max_width.map_or(1.0, |max| max as f64 / w);
+            let fit_height = self.max_height.map_or(1.0, |max| max as f64 / h);
+            fit_width.min(fit_height).min(1.0)
+        } else {
+            match (self.width, self.height) {
+                (Some(width), Some(height)) => return (width, height),
+                (Some(width), None) => width as f64 / w,
+                (None, Some(height)) => height as f64 / h,
+                (None, None) => 1.0,
+            }
+        };
+        ((w * factor) as u32, (h * factor) as u32)
+    }
+
+    /// pushes the resize onto `filters`. with `even` set the output dimensions are kept
+    /// divisible by 2, even when there's nothing to resize
+    pub fn apply(
+        &self,
+        filters: &mut FilterChain,
+        even: bool,
+        scaler: Scaler,
+    ) -> anyhow::Result<()> {
+        let bounded = self.max_width.is_some() || self.max_height.is_some();
+        let exact = self.width.is_some() || self.height.is_some();
+        if [bounded, exact, self.percent.is_some()]
+            .iter()
+            .filter(|set| **set)
+            .count()
+            > 1
+        {
+            return Err(anyhow!(
+                "resize takes either a max size, an exact size or a percentage"
+            ));
+        }
+        if [self.max_width, self.max_height, self.width, self.height].contains(&Some(0)) {
+            return Err(anyhow!("resize dimensions must be greater than 0"));
+        }
+
+        let round = |expr: String| {
+            if even {
+                format!("trunc({}/2)*2", expr)
+            } else {
+                expr
+            }
+        };
+        // -2 keeps the aspect ratio like -1, but rounds to an even number
+        let keep = if even { "-2" } else { "-1" }.to_string();
+        let exact_dimension = |dimension: u32| {
+            if even && dimension % 2 == 1 {
+                warn!(
+                    "rounding {} up to an even number for this encoder",
+                    dimension
+                );
+                dimension + 1
+            } else {
+                dimension
+            }
+        };
+
+        let scale = if let Some(percent) = self.percent {
+            if !percent.is_finite() || percent <= 0.0 {
+                return Err(anyhow!("resize percentage must be greater than 0"));
+            }
+            Scale {
+                width: round(format!("iw*{}/100", percent)),
+                height: keep,
+            }
+        } else if bounded {
+            let factor = match (self.max_width, self.max_height) {
+                (Some(width), Some(height)) => {
+                    format!("min(1\\,min({}/iw\\,{}/ih))", width, height)
+                }
+                (Some(width), None) => format!("min(1\\,{}/iw)", width),
+                (None, Some(height)) => format!("min(1\\,{}/ih)", height),
+                (None, None) => unreachable!(),
+            };
+            Scale {
+                width: round(format!("iw*{}", factor)),
+                height: round(format!("ih*{}", factor)),
+            }
+        } else {
+            match (
+                self.width.map(exact_dimension),
+                self.height.map(exact_dimension),
+            ) {
+                (Some(width), Some(height)) => {
+                    // padding and cropping only exist in software, so this always runs there
+                    let (aspect, finish) = match self.fit {
+                        ResizeFit::Fit => (
+                            "decrease",
+                            format!("pad={}:{}:(ow-iw)/2:(oh-ih)/2", width, height),
+                        ),
+                        ResizeFit::Fill => ("increase", format!("crop={}:{}", width, height)),
+                    };
+                    filters.push(format!(
+                        "scale={}:{}:force_original_aspect_ratio={}{}:flags=lanczos",
+                        width,
+                        height,
+                        aspect,
+                        if even { ":force_divisible_by=2" } else { "" }
+                    ));
+                    filters.push(finish);
+                    return Ok(());
+                }
+                (Some(width), None) => Scale {
+                    width: width.to_string(),
+                    height: keep,
+                },
+                (None, Some(height)) => Scale {
+                    width: keep,
+                    height: height.to_string(),
+                },
+                (None, None) => {
+                    if even {
+                        // odd sized sources would otherwise make the encoder bail out
+                        filters.push("crop=trunc(iw/2)*2:trunc(ih/2)*2");
+                    }
+                    return Ok(());
+                }
+            }
+        };
+
+        filters.push_scale(&scale, scaler);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrimMode {
+    /// copy the streams and start on the keyframe before `start`. fast, but usually
+    /// includes a little extra at the beginning
+    Copy,
+    /// re-encode so the cut lands exactly on `start`
+    #[default]
+    Precise,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TrimOptions {
+    /// in seconds from the start of the source
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    /// how much to keep after `start`, instead of an end
+    pub duration: Option<f64>,
+    pub mode: TrimMode,
+}
+
+impl TrimOptions {
+    pub fn is_empty(&self) -> bool {
+        self.start.is_none() && self.end.is_none() && self.duration.is_none()
+    }
+
+    /// checks the cut makes sense for a source `source_duration` seconds long
+    pub fn validate(&self, source_duration: f64) -> anyhow::Result<()> {
+        let start = self.start.unwrap_or(0.0);
+        if !start.is_finite() || start < 0.0 {
+            return Err(anyhow!("trim start can't be negative"));
+        }
+        if start >= source_duration {
+            return Err(anyhow!(
+                "trim start of {}s is past the end of the {:.1}s input",
+                start,
+                source_duration
+            ));
+        }
+
+        match (self.end, self.duration) {
+            (Some(_), Some(_)) => Err(anyhow!("trim takes either an end or a duration, not both")),
+            (Some(end), None) if end <= start => Err(anyhow!("trim end must be after the start")),
+            (None, Some(duration)) if duration <= 0.0 => {
+                Err(anyhow!("trim duration must be greater than 0"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// how long the output ends up, given how long the source is
+    pub fn trimmed_duration(&self, source_duration: f64) -> f64 {
+        let start = self.start.unwrap_or(0.0);
+        let end = self
+            .end
+            .or(self.duration.map(|duration| start + duration))
+            .unwrap_or(source_duration)
+            .min(source_duration);
+        (end - start).max(0.0)
+    }
+
+    /// args that go before the input. seeking there is fast, and still frame accurate when
+    /// re-encoding
+    pub fn input_args(&self) -> Vec<String> {
+        match self.start {
+            Some(start) if start > 0.0 => vec!["-ss".to_string(), start.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn output_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let start = self.start.unwrap_or(0.0);
+        if let Some(length) = self.end.map(|end| end - start).or(self.duration) {
+            args.extend(["-t".to_string(), length.to_string()]);
+        }
+        if self.mode == TrimMode::Copy && !self.is_empty() {
+            // copied streams start on a keyframe before the cut, which would otherwise
+            // leave negative timestamps at the start
+            args.extend(["-avoid_negative_ts".to_string(), "make_zero".to_string()]);
+        }
+        args
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TransformOptions {
+    /// in pixels of the source as it's displayed, so after any rotation the file asks for
+    pub crop: Option<CropRect>,
+    /// clockwise, in degrees. only 90, 180 and 270 are allowed
+    pub rotate: Option<u16>,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+impl TransformOptions {
+    pub fn is_empty(&self) -> bool {
+        self.crop.is_none()
+            && matches!(self.rotate, None | Some(0))
+            && !self.flip_horizontal
+            && !self.flip_vertical
+    }
+
+    /// pushes the transforms onto `filters` in the order they're applied: crop, rotate, flip
+    pub fn apply(&self, filters: &mut FilterChain) -> anyhow::Result<()> {
+        if let Some(crop) = self.crop {
+            if crop.width == 0 || crop.height == 0 {
+                return Err(anyhow!("crop width and height must be greater than 0"));
+            }
+            filters.push(format!(
+                "crop={}:{}:{}:{}",
+                crop.width, crop.height, crop.x, crop.y
+            ));
+        }
+
+        match self.rotate {
+            None | Some(0) => {}
+            Some(90) => filters.push("transpose=clock"),
+            Some(180) => {
+                filters.push("hflip");
+                filters.push("vflip");
+            }
+            Some(270) => filters.push("transpose=cclock"),
+            Some(degrees) => {
+                return Err(anyhow!(
+                    "can't rotate by {} degrees, only 90, 180 or 270",
+                    degrees
+                ));
+            }
+        }
+
+        if self.flip_horizontal {
+            filters.push("hflip");
+        }
+        if self.flip_vertical {
+            filters.push("vflip");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FrameRateMode {
+    /// drop or duplicate frames to hit the new rate
+    #[default]
+    Drop,
+    /// make up the in-between frames with motion interpolation. looks smoother, but is
+    /// very slow
+    Interpolate,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FrameRateOptions {
+    pub fps: Option<f64>,
+    pub mode: FrameRateMode,
+}
+
+impl FrameRateOptions {
+    pub fn apply(&self, filters: &mut FilterChain) -> anyhow::Result<()> {
+        let Some(fps) = self.fps else {
+            return Ok(());
+        };
+        if !fps.is_finite() || fps <= 0.0 || fps > 240.0 {
+            return Err(anyhow!("frame rate must be between 0 and 240"));
+        }
+
+        filters.push(match self.mode {
+            FrameRateMode::Drop => format!("fps={}", fps),
+            FrameRateMode::Interpolate => format!(
+                "minterpolate=fps={}:mi_mode=mci:mc_mode=aobmc:me_mode=bidir:vsbmc=1",
+                fps
+            ),
+        });
+        Ok(())
+    }
+
+    /// the frame rate the output ends up with
+    pub fn output_fps(&self, source_fps: f64) -> f64 {
+        self.fps.unwrap_or(source_fps)
+    }
+}
+
+// whether a stream was picked by index or by language
+fn selected(stream: &StreamInfo, streams: &[u32], languages: &[String]) -> bool {
+    streams.contains(&stream.index)
+        || stream
+            .tags
+            .language
+            .as_ref()
+            .is_some_and(|language| languages.contains(language))
+}
+
+/// which subtitle tracks to carry over, as far as the target container can hold them
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum SubtitleSelection {
+    #[default]
+    All,
+    None,
+    /// tracks matching either a stream index or a language tag (e.g. "eng")
+    #[serde(rename_all = "camelCase")]
+    Select {
+        #[serde(default)]
+        streams: Vec<u32>,
+        #[serde(default)]
+        languages: Vec<String>,
+    },
+}
+
+impl SubtitleSelection {
+    pub fn includes(&self, stream: &StreamInfo) -> bool {
+        match self {
+            SubtitleSelection::All => true,
+            SubtitleSelection::None => false,
+            SubtitleSelection::Select { streams, languages } => {
+                selected(stream, streams, languages)
+            }
+        }
+    }
+}
+
+/// subtitle files that can be uploaded next to the input for burning in
+pub const SUBTITLE_FILE_EXTENSIONS: [&str; 4] = ["srt", "ass", "ssa", "vtt"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BurnInSource {
+    /// an embedded subtitle stream, by stream index
+    Stream { index: u32 },
+    /// the subtitle file uploaded for the job
+    File,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SubtitlePosition {
+    #[default]
+    Bottom,
+    Middle,
+    Top,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BurnInOptions {
+    /// where the subtitles to render come from, nothing is burned in without one
+    pub source: Option<BurnInSource>,
+    /// relative to a 288 pixel tall frame, which libass scales to the real size
+    pub font_size: Option<u32>,
+    pub position: SubtitlePosition,
+    /// distance from the top or bottom edge, on the same scale as the font size
+    pub margin: Option<u32>,
+}
+
+impl BurnInOptions {
+    /// the `subtitles` filter for `input`, or None if nothing's being burned in.
+    /// `trim_start` is where the input has been seeked to, which the subtitles need to
+    /// line up with
+    pub fn filter(
+        &self,
+        input: &str,
+        subtitle_file: Option<&str>,
+        streams: &[StreamInfo],
+        trim_start: f64,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(source) = &self.source else {
+            return Ok(None);
+        };
+
+        let mut filter = match source {
+            BurnInSource::Stream { index } => {
+                // the filter counts subtitle streams only
+                let subtitles = streams.iter().filter(|s| s.is_subtitle());
+                let (position, stream) = subtitles
+                    .enumerate()
+                    .find(|(_, s)| s.index == *index)
+                    .ok_or_else(|| anyhow!("stream {} isn't a subtitle stream", index))?;
+                // libass only draws text
+                if let Some(name) = stream.codec_name.as_deref()
+                    && codec::is_bitmap_subtitle(name)
+                {
+                    return Err(anyhow!(
+                        "stream {} is a {} picture subtitle, only text subtitles can be burned in",
+                        index,
+                        name
+                    ));
+                }
+                format!("subtitles=filename={}:si={}", input, position)
+            }
+            BurnInSource::File => {
+                let file = subtitle_file
+                    .ok_or_else(|| anyhow!("no subtitle file was uploaded for this job"))?;
+                format!("subtitles=filename={}", file)
+            }
+        };
+
+        let mut style = vec![format!(
+            "Alignment={}",
+            // numpad positions, centred horizontally
+            match self.position {
+                SubtitlePosition::Bottom => 2,
+                SubtitlePosition::Middle => 5,
+                SubtitlePosition::Top => 8,
+            }
+        )];
+        match self.font_size {
+            Some(size) if size == 0 || size > 200 => {
+                return Err(anyhow!("subtitle font size must be between 1 and 200"));
+            }
+            Some(size) => style.push(format!("FontSize={}", size)),
+            None => {}
+        }
+        if let Some(margin) = self.margin {
+            style.push(format!("MarginV={}", margin));
+        }
+        filter.push_str(&format!(":force_style='{}'", style.join(",")));
+
+        // seeking the input restarts the frame timestamps at zero, but the subtitles
+        // are still timed from the start of the file
+        if trim_start > 0.0 {
+            filter = format!(
+                "setpts=PTS+{}/TB,{},setpts=PTS-STARTPTS",
+                trim_start, filter
+            );
+        }
+
+        Ok(Some(filter))
+    }
+}
+
+/// which audio tracks end up in the output
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum AudioTracks {
+    /// every track, or just the default one for containers that only hold one
+    #[default]
+    All,
+    /// tracks matching either a stream index or a language tag (e.g. "eng")
+    #[serde(rename_all = "camelCase")]
+    Select {
+        #[serde(default)]
+        streams: Vec<u32>,
+        #[serde(default)]
+        languages: Vec<String>,
+    },
+    /// every track mixed down into one
+    Mix,
+}
+
+impl AudioTracks {
+    pub fn includes(&self, stream: &StreamInfo) -> bool {
+        match self {
+            AudioTracks::All | AudioTracks::Mix => true,
+            AudioTracks::Select { streams, languages } => selected(stream, streams, languages),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MetadataMode {
+    /// carry over everything the source has
+    #[default]
+    Preserve,
+    /// drop it all, e.g. the location and device phones put in their videos
+    Strip,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MetadataOptions {
+    pub mode: MetadataMode,
+    /// container tags to set on top of the mode, an empty value removes the tag
+    pub set: BTreeMap<String, String>,
+    /// whether to keep chapters, follows the mode if not given
+    pub chapters: Option<bool>,
+    /// stripping keeps stream languages unless this is set too
+    pub strip_languages: bool,
+    /// language tags to set, keyed by source stream index. json keys are always strings,
+    /// so the index is parsed when looking it up
+    pub languages: BTreeMap<String, String>,
+}
+
+impl MetadataOptions {
+    pub fn to_args(&self, to: &ConverterFormat) -> Vec<String> {
+        let mut args = match self.mode {
+            MetadataMode::Preserve => {
+                let mut args = vec!["-map_metadata".to_string(), "0".to_string()];
+                // mp4 only writes the tags it knows about otherwise
+                if matches!(
+                    to,
+                    ConverterFormat::MP4 | ConverterFormat::MOV | ConverterFormat::M4A
+                ) {
+                    args.extend(["-movflags".to_string(), "use_metadata_tags".to_string()]);
+                }
+                args
+            }
+            // -1 on its own only stops the global tags being copied, the streams would
+            // still carry creation times and device names. bitexact keeps the muxer from
+            // adding its own encoder tag
+            MetadataMode::Strip => [
+                "-map_metadata",
+                "-1",
+                "-map_metadata:s",
+                "-1",
+                "-fflags",
+                "+bitexact",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+
+        let chapters = self.chapters.unwrap_or(self.mode == MetadataMode::Preserve);
+        args.extend([
+            "-map_chapters".to_string(),
+            if chapters { "0" } else { "-1" }.to_string(),
+        ]);
+
+        for (key, value) in &self.set {
+            // ffmpeg would read anything after an = as part of the value
+            if key.is_empty() || key.contains('=') {
+                warn!("ignoring invalid metadata key {:?}", key);
+                continue;
+            }
+            args.extend(["-metadata".to_string(), format!("{}={}", key, value)]);
+        }
+        for index in self.languages.keys() {
+            if index.parse::<u32>().is_err() {
+                warn!(
+                    "ignoring language for {:?}, which isn't a stream index",
+                    index
+                );
+            }
+        }
+        args
+    }
+
+    /// tags for the `n`th output stream of `kind` (v, a or s), which comes from `stream`
+    pub fn stream_args(&self, kind: char, n: usize, stream: &StreamInfo) -> Vec<String> {
+        // preserved streams already bring their tags along
+        let language = self
+            .languages
+            .iter()
+            .find(|(index, _)| index.parse::<u32>().ok() == Some(stream.index))
+            .map(|(_, language)| language)
+            .or_else(|| {
+                (self.mode == MetadataMode::Strip && !self.strip_languages)
+                    .then_some(stream.tags.language.as_ref())
+                    .flatten()
+            });
+
+        match language {
+            Some(language) => vec![
+                format!("-metadata:s:{}:{}", kind, n),
+                format!("language={}", language),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TonemapMode {
+    /// tonemap hdr inputs when the output can't carry hdr
+    #[default]
+    Auto,
+    /// tonemap hdr inputs even when the output could stay hdr, for players that can't show it
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TonemapAlgorithm {
+    /// keeps highlight detail, a little dark overall
+    #[default]
+    Hable,
+    Mobius,
+    Reinhard,
+    /// just cuts off everything too bright
+    Clip,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TonemapOptions {
+    pub mode: TonemapMode,
+    pub algorithm: TonemapAlgorithm,
+}
+
+impl TonemapOptions {
+    /// whether an hdr input gets tonemapped, given whether the output could carry it as is
+    pub fn wanted(&self, output_carries_hdr: bool) -> bool {
+        match self.mode {
+            TonemapMode::Auto => !output_carries_hdr,
+            TonemapMode::Always => true,
+            TonemapMode::Never => false,
+        }
+    }
+
+    /// hdr to bt.709 sdr. tonemapping has to happen on linear light, hence the round trip
+    pub fn filters(&self) -> Vec<String> {
+        let algorithm = match self.algorithm {
+            TonemapAlgorithm::Hable => "hable",
+            TonemapAlgorithm::Mobius => "mobius",
+            TonemapAlgorithm::Reinhard => "reinhard",
+            TonemapAlgorithm::Clip => "clip",
+        };
+        vec![
+            "zscale=t=linear:npl=100".to_string(),
+            "format=gbrpf32le".to_string(),
+            "zscale=p=bt709".to_string(),
+            format!("tonemap=tonemap={}:desat=0", algorithm),
+            "zscale=t=bt709:m=bt709:r=tv".to_string(),
+            "format=yuv420p".to_string(),
+        ]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LoudnormOptions {
+    /// integrated loudness to aim for, in LUFS
+    pub target: f64,
+    /// in dBTP
+    pub true_peak: f64,
+    /// loudness range, in LU
+    pub range: f64,
+}
+
+// what EBU R128 asks for
+impl Default for LoudnormOptions {
+    fn default() -> Self {
+        Self {
+            target: -23.0,
+            true_peak: -1.0,
+            range: 7.0,
+        }
+    }
+}
+
+impl LoudnormOptions {
+    /// checks the targets are within what loudnorm accepts
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(-70.0..=-5.0).contains(&self.target) {
+            return Err(anyhow!("loudness target must be between -70 and -5 LUFS"));
+        }
+        if !(-9.0..=0.0).contains(&self.true_peak) {
+            return Err(anyhow!("true peak must be between -9 and 0 dBTP"));
+        }
+        if !(1.0..=50.0).contains(&self.range) {
+            return Err(anyhow!("loudness range must be between 1 and 50 LU"));
+        }
+        Ok(())
+    }
+
+    pub fn params(&self) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}",
+            self.target, self.true_peak, self.range
+        )
+    }
+
+    /// the second pass, correcting by what the first one `measured`. loudnorm works at
+    /// 192kHz internally, so it's resampled back to `sample_rate` afterwards
+    pub fn filters(&self, measured: &Loudness, sample_rate: u32) -> Vec<String> {
+        vec![
+            format!(
+                "{}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+                self.params(),
+                measured.integrated,
+                measured.true_peak,
+                measured.range,
+                measured.threshold,
+                measured.offset
+            ),
+            format!("aresample={}", sample_rate),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn target_bitrate_leaves_margin_and_audio() {
+        // 25MB over 100s is 2Mbps, less the 4% margin and the audio
+        let bitrate = RateControl::target_bitrate(25_000_000, 100.0, 128_000).unwrap();
+        assert_eq!(bitrate, 1_920_000 - 128_000);
+    }
+
+    #[test]
+    fn target_bitrate_rejects_too_small() {
+        assert!(RateControl::target_bitrate(100_000, 100.0, 0).is_err());
+        // the audio alone eats the budget
+        assert!(RateControl::target_bitrate(1_000_000, 60.0, 128_000).is_err());
+        assert!(RateControl::target_bitrate(25_000_000, 0.0, 0).is_err());
+    }
+
+    #[test]
+    fn quality_maps_onto_encoder_scale() {
+        assert_eq!(quality_scale(100, 16, 40), "16");
+        assert_eq!(quality_scale(0, 16, 40), "40");
+        assert_eq!(quality_scale(50, 16, 40), "28");
+        assert_eq!(quality_scale(200, 16, 40), "16");
+
+        let quality = RateControl::Quality { quality: 50 };
+        assert_eq!(quality.to_args("libx264", 0), args(&["-crf", "28"]));
+    }
+
+    #[test]
+    fn gif_loop_count_becomes_repeats() {
+        let gif = |loop_count| GifOptions {
+            loop_count,
+            ..Default::default()
+        };
+        assert_eq!(gif(None).output_args(), args(&["-loop", "0"]));
+        assert_eq!(gif(Some(0)).output_args(), args(&["-loop", "0"]));
+        assert_eq!(gif(Some(1)).output_args(), args(&["-loop", "-1"]));
+        assert_eq!(gif(Some(3)).output_args(), args(&["-loop", "2"]));
+    }
+
+    #[test]
+    fn trim_length_comes_from_end_or_duration() {
+        let end = TrimOptions {
+            start: Some(10.0),
+            end: Some(25.5),
+            ..Default::default()
+        };
+        assert_eq!(end.output_args(), args(&["-t", "15.5"]));
+        assert_eq!(end.input_args(), args(&["-ss", "10"]));
+
+        let duration = TrimOptions {
+            start: Some(10.0),
+            duration: Some(5.0),
+            ..Default::default()
+        };
+        assert_eq!(duration.output_args(), args(&["-t", "5"]));
+
+        let copy = TrimOptions {
+            start: Some(10.0),
+            mode: TrimMode::Copy,
+            ..Default::default()
+        };
+        assert_eq!(
+            copy.output_args(),
+            args(&["-avoid_negative_ts", "make_zero"])
+        );
+    }
+}
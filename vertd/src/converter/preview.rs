@@ -0,0 +1,149 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use super::{job::Job, options::ImageFormat};
+
+pub const MAX_STORYBOARD_FRAMES: u32 = 100;
+pub const MAX_PREVIEW_WIDTH: u32 = 1920;
+
+// runs ffmpeg with `args` and hands back whatever image it wrote to stdout
+async fn render(args: Vec<String>, format: &ImageFormat) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(args)
+        .args(format.encoder_args())
+        .args(["-f", "image2pipe", "pipe:1"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to spawn ffmpeg: {}", e))?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(anyhow!(
+            "failed to render preview: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+/// grabs the frame shown at `time` seconds into the job's input
+pub async fn frame_at(job: &mut Job, time: f64, format: &ImageFormat) -> anyhow::Result<Vec<u8>> {
+    if !job.has_video().await? {
+        return Err(anyhow!("input has no video stream"));
+    }
+
+    let duration = job.duration().await?;
+    if !(0.0..=duration).contains(&time) {
+        return Err(anyhow!("time must be between 0 and {}", duration));
+    }
+
+    render(
+        vec![
+            // seeking before -i is fast and still frame-accurate when transcoding
+            "-ss".to_string(),
+            time.to_string(),
+            "-i".to_string(),
+            format!("input/{}.{}", job.id, job.from),
+            "-frames:v".to_string(),
+            "1".to_string(),
+        ],
+        format,
+    )
+    .await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoryboardTile {
+    pub time: f64,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// a sprite sheet of evenly spaced frames, laid out left to right, top to bottom
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Storyboard {
+    pub columns: u32,
+    pub rows: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tiles: Vec<StoryboardTile>,
+}
+
+impl Storyboard {
+    pub async fn new(
+        job: &mut Job,
+        count: u32,
+        columns: u32,
+        tile_width: u32,
+    ) -> anyhow::Result<Self> {
+        if !(1..=MAX_STORYBOARD_FRAMES).contains(&count) {
+            return Err(anyhow!(
+                "frame count must be between 1 and {}",
+                MAX_STORYBOARD_FRAMES
+            ));
+        }
+        if !(1..=count).contains(&columns) {
+            return Err(anyhow!("columns must be between 1 and the frame count"));
+        }
+        if !(16..=MAX_PREVIEW_WIDTH).contains(&tile_width) {
+            return Err(anyhow!(
+                "tile width must be between 16 and {}",
+                MAX_PREVIEW_WIDTH
+            ));
+        }
+        if !job.has_video().await? {
+            return Err(anyhow!("input has no video stream"));
+        }
+
+        let duration = job.duration().await?;
+        let (width, height) = job.resolution().await?;
+        // keep the source aspect ratio, rounded to an even height like `scale=w:-2` would
+        let tile_height =
+            (((tile_width as f64 * height as f64 / width as f64) / 2.0).round() as u32 * 2).max(2);
+
+        let rows = count.div_ceil(columns);
+        let tiles = (0..count)
+            .map(|i| StoryboardTile {
+                time: duration * i as f64 / count as f64,
+                x: (i % columns) * tile_width,
+                y: (i / columns) * tile_height,
+            })
+            .collect();
+
+        Ok(Self {
+            columns,
+            rows,
+            tile_width,
+            tile_height,
+            tiles,
+        })
+    }
+
+    pub async fn render(&self, job: &mut Job, format: &ImageFormat) -> anyhow::Result<Vec<u8>> {
+        let duration = job.duration().await?;
+        let count = self.tiles.len() as f64;
+        render(
+            vec![
+                "-i".to_string(),
+                format!("input/{}.{}", job.id, job.from),
+                "-vf".to_string(),
+                format!(
+                    "fps={},scale={}:{}:flags=lanczos,tile={}x{}",
+                    count / duration,
+                    self.tile_width,
+                    self.tile_height,
+                    self.columns,
+                    self.rows
+                ),
+                "-frames:v".to_string(),
+                "1".to_string(),
+            ],
+            format,
+        )
+        .await
+    }
+}
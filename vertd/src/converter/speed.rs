@@ -1,7 +1,7 @@
 use log::warn;
 use serde::{Deserialize, Serialize};
 
-use super::{format::ConverterFormat, gpu::ConverterGPU};
+use super::{format::ConverterFormat, options::RateControl};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -26,7 +26,116 @@ impl ConversionSpeed {
         }
     }
 
-    pub fn to_args(&self, to: &ConverterFormat, gpu: &ConverterGPU, bitrate: u64) -> Vec<String> {
+    // every video encoder has its own idea of what a speed preset looks like
+    fn encoder_args(&self, encoder: &str) -> Vec<String> {
+        let (flag, value) = match encoder {
+            e if e.ends_with("_nvenc") => (
+                "-preset",
+                // only "slow", "medium", and "fast" are supported
+                match self {
+                    ConversionSpeed::VerySlow | ConversionSpeed::Slower => "slow",
+                    ConversionSpeed::Slow | ConversionSpeed::Medium => "medium",
+                    ConversionSpeed::Fast | ConversionSpeed::UltraFast => "fast",
+                },
+            ),
+
+            e if e.ends_with("_qsv") => (
+                "-preset",
+                // same names as x264, minus ultrafast
+                match self {
+                    ConversionSpeed::UltraFast => "veryfast",
+                    ConversionSpeed::Fast => "fast",
+                    ConversionSpeed::Medium => "medium",
+                    ConversionSpeed::Slow => "slow",
+                    ConversionSpeed::Slower => "slower",
+                    ConversionSpeed::VerySlow => "veryslow",
+                },
+            ),
+
+            // libx264 and libx265 share the same preset names
+            "libx264" | "libx265" => (
+                "-preset",
+                match self {
+                    ConversionSpeed::UltraFast => "ultrafast",
+                    ConversionSpeed::Fast => "fast",
+                    ConversionSpeed::Medium => "medium",
+                    ConversionSpeed::Slow => "slow",
+                    ConversionSpeed::Slower => "slower",
+                    ConversionSpeed::VerySlow => "veryslow",
+                },
+            ),
+
+            // 0 (slowest) to 13 (fastest), anything under 4 is painfully slow
+            "libsvtav1" => (
+                "-preset",
+                match self {
+                    ConversionSpeed::UltraFast => "12",
+                    ConversionSpeed::Fast => "10",
+                    ConversionSpeed::Medium => "8",
+                    ConversionSpeed::Slow => "6",
+                    ConversionSpeed::Slower => "5",
+                    ConversionSpeed::VerySlow => "4",
+                },
+            ),
+
+            // 0 (slowest) to 8 (fastest)
+            "libaom-av1" => (
+                "-cpu-used",
+                match self {
+                    ConversionSpeed::UltraFast => "8",
+                    ConversionSpeed::Fast => "6",
+                    ConversionSpeed::Medium => "4",
+                    ConversionSpeed::Slow => "3",
+                    ConversionSpeed::Slower => "2",
+                    ConversionSpeed::VerySlow => "1",
+                },
+            ),
+
+            // 0 (slowest) to 5 (fastest) with the "good" deadline
+            "libvpx-vp9" => {
+                let cpu_used = match self {
+                    ConversionSpeed::UltraFast => "5",
+                    ConversionSpeed::Fast => "4",
+                    ConversionSpeed::Medium => "3",
+                    ConversionSpeed::Slow => "2",
+                    ConversionSpeed::Slower => "1",
+                    ConversionSpeed::VerySlow => "0",
+                };
+                return vec![
+                    "-deadline".to_string(),
+                    "good".to_string(),
+                    "-cpu-used".to_string(),
+                    cpu_used.to_string(),
+                    "-row-mt".to_string(),
+                    "1".to_string(),
+                ];
+            }
+
+            "libvpx" => (
+                "-speed",
+                match self {
+                    ConversionSpeed::UltraFast => "4",
+                    ConversionSpeed::Fast => "3",
+                    ConversionSpeed::Medium => "2",
+                    ConversionSpeed::Slow => "1",
+                    ConversionSpeed::Slower => "0",
+                    ConversionSpeed::VerySlow => "-1",
+                },
+            ),
+
+            _ => return vec![],
+        };
+
+        vec![flag.to_string(), value.to_string()]
+    }
+
+    pub fn to_args(
+        &self,
+        to: &ConverterFormat,
+        encoder: Option<&str>,
+        rate_control: &RateControl,
+        bitrate: u64,
+    ) -> Vec<String> {
         let mut args = Vec::new();
 
         match to {
@@ -35,56 +144,54 @@ impl ConversionSpeed {
             | ConverterFormat::MOV
             | ConverterFormat::MTS
             | ConverterFormat::TS
-            | ConverterFormat::M2TS => {
-                args.push("-preset".to_string());
-                match gpu {
-                    ConverterGPU::NVIDIA => match self {
-                        // only "slow", "medium", and "fast" are supported
-                        ConversionSpeed::VerySlow | ConversionSpeed::Slower => {
-                            args.push("slow".to_string())
-                        }
-                        ConversionSpeed::Slow | ConversionSpeed::Medium => {
-                            args.push("medium".to_string())
-                        }
-                        ConversionSpeed::Fast | ConversionSpeed::UltraFast => {
-                            args.push("fast".to_string())
-                        }
-                    },
-
-                    _ => match self {
-                        ConversionSpeed::UltraFast => args.push("ultrafast".to_string()),
-                        ConversionSpeed::Fast => args.push("fast".to_string()),
-                        ConversionSpeed::Medium => args.push("medium".to_string()),
-                        ConversionSpeed::Slow => args.push("slow".to_string()),
-                        ConversionSpeed::Slower => args.push("slower".to_string()),
-                        ConversionSpeed::VerySlow => args.push("veryslow".to_string()),
-                    },
-                }
+            | ConverterFormat::M2TS
+            | ConverterFormat::WebM
+            | ConverterFormat::AVI => {
+                args.extend(self.encoder_args(encoder.unwrap_or_default()));
             }
 
-            ConverterFormat::GIF => {}
+            ConverterFormat::GIF | ConverterFormat::ZIP => {}
+
+            // compression_level only trades encode time for file size here
+            ConverterFormat::WebP => {
+                args.push("-compression_level".to_string());
+                match self {
+                    ConversionSpeed::UltraFast => args.push("0".to_string()),
+                    ConversionSpeed::Fast => args.push("2".to_string()),
+                    ConversionSpeed::Medium => args.push("4".to_string()),
+                    ConversionSpeed::Slow => args.push("5".to_string()),
+                    ConversionSpeed::Slower | ConversionSpeed::VerySlow => {
+                        args.push("6".to_string())
+                    }
+                };
+            }
 
-            ConverterFormat::WebM | ConverterFormat::AVI => {
-                args.push("-speed".to_string());
+            ConverterFormat::APNG => {
+                args.push("-compression_level".to_string());
                 match self {
-                    ConversionSpeed::UltraFast => args.push("4".to_string()),
+                    ConversionSpeed::UltraFast => args.push("1".to_string()),
                     ConversionSpeed::Fast => args.push("3".to_string()),
-                    ConversionSpeed::Medium => args.push("2".to_string()),
-                    ConversionSpeed::Slow => args.push("1".to_string()),
-                    ConversionSpeed::Slower => args.push("0".to_string()),
-                    ConversionSpeed::VerySlow => args.push("-1".to_string()),
+                    ConversionSpeed::Medium => args.push("6".to_string()),
+                    ConversionSpeed::Slow => args.push("7".to_string()),
+                    ConversionSpeed::Slower => args.push("8".to_string()),
+                    ConversionSpeed::VerySlow => args.push("9".to_string()),
                 };
             }
 
+            ConverterFormat::MP3
+            | ConverterFormat::M4A
+            | ConverterFormat::FLAC
+            | ConverterFormat::WAV
+            | ConverterFormat::OGG
+            | ConverterFormat::Opus => {}
+
             ConverterFormat::WMV => {
                 warn!("wmv format does not support speed settings");
             }
         };
 
-        if *to != ConverterFormat::GIF {
-            args.push("-b:v".to_string());
-            let bitrate = (bitrate as f64 * self.to_bitrate_mul()) as u64;
-            args.push(bitrate.to_string());
+        if to.is_video() {
+            args.extend(rate_control.to_args(encoder.unwrap_or_default(), bitrate));
         }
 
         args